@@ -1,13 +1,87 @@
+use crate::function;
 use crate::lexer::{Lexer, TokenKind};
 use crate::parser;
+use crate::source::Source;
 use crate::vm::{Chunk, Opcode};
 use miette::Result;
+use std::rc::Rc;
 
 fn traverse_and_compile(nodes: parser::Nodes, chunk: &mut Chunk) {
     use parser::Nodes::*;
+    // Literals too large for a float, such as `1e400`, overflowed to infinity
+    if let Number(span, number) | Imaginary(span, number) | Quantity(span, number, _) = &nodes
+        && !number.is_finite()
+    {
+        chunk.push(Opcode::Overflowed(*span));
+    }
     match nodes {
-        Number(offset, number) => chunk.push(Opcode::Num(offset, number)),
+        Number(span, number) | Constant(span, _, number) => chunk.push(Opcode::Num(span, number)),
+        Int(span, number) => chunk.push(Opcode::Int(span, number)),
+        Imaginary(span, number) => chunk.push(Opcode::Imaginary(span, number)),
+        Str(span, text) => chunk.push(Opcode::Str(span, text)),
+        Variable(span, name) => chunk.push(Opcode::GetVar(span, name)),
+        Call(span, native, args) => {
+            let argc = args.len();
+            for arg in args {
+                traverse_and_compile(arg, chunk);
+            }
+            chunk.push(Opcode::Call(span, native, argc));
+        }
+        Import(span, path) => chunk.push(Opcode::Import(span, path)),
+        Assign(name, node) => {
+            traverse_and_compile(*node, chunk);
+            chunk.push(Opcode::SetVar(name));
+        }
+        Range(start, end, inclusive) => {
+            traverse_and_compile(*start, chunk);
+            traverse_and_compile(*end, chunk);
+            chunk.push(Opcode::Range(inclusive));
+        }
+        // The iterable, the results so far and the position stay on the stack while
+        // the body runs, every pass collects the value of its last statement
+        For(name, iterable, body) => {
+            traverse_and_compile(*iterable, chunk);
+            chunk.push(Opcode::Iter);
+            let head = chunk.len();
+            chunk.push(Opcode::Next(name, 0));
+            compile_statements(body, chunk, Opcode::Collect);
+            chunk.push(Opcode::Loop(head));
+            let exit = chunk.len();
+            if let Opcode::Next(_, target) = &mut chunk[head] {
+                *target = exit;
+            }
+        }
+        // The body gets a chunk of its own, it runs whenever the function is called
+        Lambda(span, params, body) => {
+            let mut names = Vec::new();
+            variables(&body, &mut names);
+            names.retain(|name| !params.contains(name));
+            let mut body_chunk = Chunk::new();
+            traverse_and_compile(*body, &mut body_chunk);
+            body_chunk.push(Opcode::Ret);
+            chunk.push(Opcode::Closure(Rc::new(function::Lambda {
+                params,
+                names,
+                chunk: Rc::new(body_chunk),
+                span,
+            })));
+        }
+        Apply(callee, args, span) => {
+            let argc = args.len();
+            traverse_and_compile(*callee, chunk);
+            for arg in args {
+                traverse_and_compile(arg, chunk);
+            }
+            chunk.push(Opcode::Apply(span, argc));
+        }
+        Quantity(span, number, unit) => chunk.push(Opcode::Quantity(span, number, unit)),
+        Convert(node, span, unit) => {
+            traverse_and_compile(*node, chunk);
+            chunk.push(Opcode::Convert(span, unit));
+        }
         Operator(op_node) => {
+            // `200 + 15%` adds fifteen percent of the left operand, like a desk calculator
+            let of_left = matches!(op_node.right.as_deref(), Some(Percent(_)));
             if let Some(node) = op_node.left {
                 traverse_and_compile(*node, chunk);
             }
@@ -15,11 +89,21 @@ fn traverse_and_compile(nodes: parser::Nodes, chunk: &mut Chunk) {
                 traverse_and_compile(*node, chunk)
             }
             match op_node.op.kind {
+                TokenKind::Plus if of_left => chunk.push(Opcode::PercentAdd),
+                TokenKind::Minus if of_left => chunk.push(Opcode::PercentSub),
                 TokenKind::Plus => chunk.push(Opcode::Add),
                 TokenKind::Minus => chunk.push(Opcode::Sub),
                 TokenKind::Div => chunk.push(Opcode::Div),
-                TokenKind::Mult => chunk.push(Opcode::Mult),
+                TokenKind::IntDiv => chunk.push(Opcode::IntDiv),
+                TokenKind::Mult | TokenKind::Of => chunk.push(Opcode::Mult),
+                TokenKind::MatMul => chunk.push(Opcode::MatMul),
                 TokenKind::Mod => chunk.push(Opcode::Mod),
+                TokenKind::BitAnd => chunk.push(Opcode::BitAnd),
+                TokenKind::BitOr => chunk.push(Opcode::BitOr),
+                TokenKind::Xor => chunk.push(Opcode::Xor),
+                TokenKind::Shl => chunk.push(Opcode::Shl),
+                TokenKind::Shr => chunk.push(Opcode::Shr),
+                TokenKind::Caret => chunk.push(Opcode::Pow),
                 _ => unreachable!(),
             }
         }
@@ -31,27 +115,131 @@ fn traverse_and_compile(nodes: parser::Nodes, chunk: &mut Chunk) {
             traverse_and_compile(*node, chunk);
             chunk.push(Opcode::Nop);
         }
+        Percent(node) => {
+            traverse_and_compile(*node, chunk);
+            chunk.push(Opcode::Percent);
+        }
+        parser::Nodes::BitNot(node) => {
+            traverse_and_compile(*node, chunk);
+            chunk.push(Opcode::BitNot);
+        }
+        parser::Nodes::List(span, items) => {
+            let len = items.len();
+            for item in items {
+                traverse_and_compile(item, chunk);
+            }
+            chunk.push(Opcode::List(span, len));
+        }
+        parser::Nodes::Index(list, index, span) => {
+            traverse_and_compile(*list, chunk);
+            traverse_and_compile(*index, chunk);
+            chunk.push(Opcode::Index(span));
+        }
+        parser::Nodes::Factorial(node, span) => {
+            traverse_and_compile(*node, chunk);
+            chunk.push(Opcode::Fact(span));
+        }
+    }
+}
+
+// Collects the names of the variables a node reads or assigns
+fn variables(node: &parser::Nodes, names: &mut Vec<String>) {
+    use parser::Nodes::*;
+    let mut add = |name: &String| {
+        if !names.contains(name) {
+            names.push(name.clone());
+        }
+    };
+    match node {
+        Variable(_, name) => add(name),
+        Assign(name, node) => {
+            add(name);
+            variables(node, names);
+        }
+        For(name, iterable, body) => {
+            add(name);
+            variables(iterable, names);
+            body.iter().for_each(|node| variables(node, names));
+        }
+        // The parameters of a nested lambda are its own rather than variables
+        Lambda(_, params, node) => {
+            let mut inner = Vec::new();
+            variables(node, &mut inner);
+            inner
+                .iter()
+                .filter(|name| !params.contains(name))
+                .for_each(add);
+        }
+        Negative(node)
+        | Positive(node)
+        | BitNot(node)
+        | Percent(node)
+        | Factorial(node, _)
+        | Convert(node, _, _) => variables(node, names),
+        Range(left, right, _) | Index(left, right, _) => {
+            variables(left, names);
+            variables(right, names);
+        }
+        Operator(op_node) => {
+            for node in [&op_node.left, &op_node.right].into_iter().flatten() {
+                variables(node, names);
+            }
+        }
+        Apply(callee, args, _) => {
+            variables(callee, names);
+            args.iter().for_each(|node| variables(node, names));
+        }
+        Call(_, _, items) | List(_, items) => items.iter().for_each(|node| variables(node, names)),
+        Number(..) | Int(..) | Imaginary(..) | Str(..) | Constant(..) | Import(..)
+        | Quantity(..) => (),
     }
 }
 
 pub fn compile(source: &str) -> Result<Chunk> {
+    compile_source(source.into())
+}
+
+/// Compiles a named source, such as a script file.
+pub fn compile_source(source: Source) -> Result<Chunk> {
     let lexer = Lexer::new(source);
-    let ast = parser::parse(lexer.source, &mut lexer.peekable(), 0)?;
+    let statements = parser::parse_program(lexer.source, &mut lexer.peekable())?;
     let mut chunk = Chunk::new();
-    traverse_and_compile(ast, &mut chunk);
-    chunk.push(Opcode::Ret);
+    compile_statements(statements, &mut chunk, Opcode::Ret);
     Ok(chunk)
 }
 
+// The last statement ends with `last`, the others are printed or dropped
+fn compile_statements(statements: Vec<parser::Nodes>, chunk: &mut Chunk, last: Opcode) {
+    let count = statements.len();
+    for (i, statement) in statements.into_iter().enumerate() {
+        // Assignments and imports only print when they are the last statement
+        let assignment = matches!(
+            statement,
+            parser::Nodes::Assign(..) | parser::Nodes::Import(..)
+        );
+        traverse_and_compile(statement, chunk);
+        if i + 1 < count {
+            chunk.push(if assignment {
+                Opcode::Pop
+            } else {
+                Opcode::Print
+            });
+        }
+    }
+    chunk.push(last);
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
-    use crate::Vm;
+    use crate::natives::ModMode;
+    use crate::vm::Vm;
+    use crate::vm::{Echo, Globals, Settings};
 
     #[test]
     fn reg_num_compilation() {
         let source = "(1 + 2) * 3";
-        let chunk = compile(&source).unwrap();
+        let chunk = compile(source).unwrap();
         let mut vm = Vm::new(source, chunk);
         let result = vm.eval().unwrap();
         assert_eq!(result, "9");
@@ -60,16 +248,845 @@ mod test {
     #[test]
     fn neg_num_compilation() {
         let source = "-(3 + 2)";
-        let chunk = compile(&source).unwrap();
+        let chunk = compile(source).unwrap();
         let mut vm = Vm::new(source, chunk);
         let result = vm.eval().unwrap();
         assert_eq!(result, "-5");
     }
 
+    #[test]
+    fn bitwise_compilation() {
+        let source = "0xF0 | 0b0101 xor 3 & ~0 << 1";
+        let chunk = compile(source).unwrap();
+        let mut vm = Vm::new(source, chunk);
+        let result = vm.eval().unwrap();
+        assert_eq!(result, "247");
+    }
+
+    #[test]
+    fn power_compilation() {
+        for (source, expected) in [
+            ("2^10", "1024"),
+            ("2^3^2", "512"),
+            ("-2^2", "-4"),
+            ("2^-1", "0.5"),
+            ("[1, 2, 3]^2", "[1, 4, 9]"),
+            ("3 m^2 * 2", "6 m^2"),
+        ] {
+            let chunk = compile(source).unwrap();
+            let mut vm = Vm::new(source, chunk);
+            assert_eq!(vm.eval().unwrap(), expected, "{source}");
+        }
+        let source = "1 + 0^-1";
+        let err = Vm::new(source, compile(source).unwrap())
+            .eval()
+            .unwrap_err();
+        let label = err.labels().unwrap().next().unwrap();
+        assert_eq!((label.offset(), label.len()), (4, 4));
+    }
+
+    #[test]
+    fn wide_bitwise() {
+        let hex = Settings {
+            base: crate::format::Base::Hex,
+            ..Default::default()
+        };
+        for (source, expected, in_hex) in [
+            ("(1 << 60) | 1", "1152921504606846977", "0x1000000000000001"),
+            ("1 << 63", "9223372036854775808", "0x8000000000000000"),
+            ("0xFFFFFFFFFFFFFFFF & 0xFF", "255", "0xff"),
+            (
+                "0xFFFF_FFFF_FFFF_FFFF xor 1",
+                "18446744073709551614",
+                "0xfffffffffffffffe",
+            ),
+            ("0xFFFFFFFFFFFFFFFF >> 60", "15", "0xf"),
+            (
+                "~(1 << 60) & (1 << 61 | 1 << 60)",
+                "2305843009213693952",
+                "0x2000000000000000",
+            ),
+            ("-(1 << 60) >> 59", "-2", "-0x2"),
+            (
+                "0xFFFFFFFFFFFFFFFFFF",
+                "4722366482869645213695",
+                "0xffffffffffffffffff",
+            ),
+            ("0x1_0000_0000_0000_0000 >> 60", "16", "0x10"),
+        ] {
+            let chunk = compile(source).unwrap();
+            assert_eq!(Vm::new(source, chunk).eval().unwrap(), expected, "{source}");
+            let chunk = compile(source).unwrap();
+            let mut vm = Vm::new(source, chunk).with_settings(hex);
+            assert_eq!(vm.eval().unwrap(), in_hex, "{source}");
+        }
+    }
+
+    #[test]
+    fn unit_compilation() {
+        for (source, expected) in [
+            ("3 km + 200 m", "3.2 km"),
+            ("60 mph to m/s", "26.8224 m/s"),
+            ("5 kg * 9.81 m/s^2", "49.050000000000004 kg*m/s^2"),
+            ("5 kg * 9.81 m/s^2 in N", "49.050000000000004 N"),
+            ("1 km / 250 m", "4"),
+            ("2 h * 3", "6 h"),
+        ] {
+            let chunk = compile(source).unwrap();
+            let mut vm = Vm::new(source, chunk);
+            assert_eq!(vm.eval().unwrap(), expected, "{source}");
+        }
+    }
+
+    #[test]
+    fn unit_power_errors() {
+        for (source, span) in [
+            ("1 m^100 * 1 m^100", (0, 7)),
+            ("$x = 1 m^127; $x * $x", (14, 2)),
+            ("1 m^-127 / 1 m^2", (0, 8)),
+        ] {
+            let chunk = compile(source).unwrap();
+            let err = Vm::new(source, chunk).eval().unwrap_err();
+            assert_eq!(err.to_string(), "Unit power out of range!", "{source}");
+            let label = err.labels().unwrap().next().unwrap();
+            assert_eq!((label.offset(), label.len()), span, "{source}");
+        }
+        let Err(err) = compile("1 m^100*m^100") else {
+            panic!("the unit should not parse");
+        };
+        assert_eq!(err.to_string(), "Unit power out of range!");
+        // Dimensions are wider than unit powers, a litre is a cubic length
+        let source = "1 L^50";
+        let mut vm = Vm::new(source, compile(source).unwrap());
+        assert_eq!(vm.eval().unwrap(), "1 L^50");
+    }
+
+    #[test]
+    fn unit_dimension_mismatch() {
+        let source = "3 m + 2 s";
+        let chunk = compile(source).unwrap();
+        let mut vm = Vm::new(source, chunk);
+        let err = vm.eval().unwrap_err();
+        let labels: Vec<_> = err
+            .labels()
+            .unwrap()
+            .map(|label| (label.offset(), label.len()))
+            .collect();
+        assert_eq!(labels, vec![(0, 3), (6, 3)]);
+    }
+
+    #[test]
+    fn percent_compilation() {
+        for (source, expected) in [
+            ("200 + 15%", "230"),
+            ("200 - 15%", "170"),
+            ("200 + 15% + 5", "235"),
+            ("200 + 15% - 5", "225"),
+            ("50% - 1", "-0.5"),
+            ("200 * 15%", "30"),
+            ("15% of 200", "30"),
+            ("15%", "0.15"),
+            ("80 km + 25%", "100 km"),
+            ("7 % 3", "1"),
+        ] {
+            let chunk = compile(source).unwrap();
+            let mut vm = Vm::new(source, chunk);
+            assert_eq!(vm.eval().unwrap(), expected, "{source}");
+        }
+    }
+
+    #[test]
+    fn constant_compilation() {
+        let source = "2 * pi - tau";
+        let chunk = compile(source).unwrap();
+        let mut vm = Vm::new(source, chunk);
+        assert_eq!(vm.eval().unwrap(), "0");
+    }
+
+    #[test]
+    fn variable_compilation() {
+        let mut globals = Globals::new();
+        for (source, expected) in [("$r = 2 m", "2 m"), ("$r * $r * 3", "12 m^2")] {
+            let chunk = compile(source).unwrap();
+            let mut vm = Vm::new(source, chunk).with_globals(globals);
+            assert_eq!(vm.eval().unwrap(), expected, "{source}");
+            globals = vm.into_globals();
+        }
+        let source = "$unset + 1";
+        let chunk = compile(source).unwrap();
+        let mut vm = Vm::new(source, chunk).with_globals(globals);
+        let err = vm.eval().unwrap_err();
+        let label = err.labels().unwrap().next().unwrap();
+        assert_eq!((label.offset(), label.len()), (0, 6));
+    }
+
+    #[test]
+    fn call_compilation() {
+        for (source, expected) in [
+            ("sqrt(16) + abs(-2)", "6"),
+            ("max(1, 7, 3) - min(4, 2)", "5"),
+            ("round(pi, 2)", "3.14"),
+            ("log(1000) + ln(e)", "4"),
+            ("floor(2.7) + ceil(2.2) + trunc(-1.5)", "4"),
+            ("hypot(3, 4)", "5"),
+        ] {
+            let chunk = compile(source).unwrap();
+            let mut vm = Vm::new(source, chunk);
+            assert_eq!(vm.eval().unwrap(), expected, "{source}");
+        }
+    }
+
+    #[test]
+    fn domain_errors() {
+        for (source, span) in [
+            ("1 + sqrt(-4)", (4, 8)),
+            ("log(-1, 10)", (0, 11)),
+            ("exp(1000)", (0, 9)),
+            ("ln(0)", (0, 5)),
+            ("asin(2)", (0, 7)),
+        ] {
+            let chunk = compile(source).unwrap();
+            let mut vm = Vm::new(source, chunk);
+            let err = vm.eval().unwrap_err();
+            let label = err.labels().unwrap().next().unwrap();
+            assert_eq!((label.offset(), label.len()), span, "{source}");
+        }
+    }
+
+    #[test]
+    fn ieee_results() {
+        let settings = Settings {
+            ieee: true,
+            ..Default::default()
+        };
+        for (source, expected) in [
+            ("sqrt(-4)", "NaN"),
+            ("ln(0)", "-inf"),
+            ("0 / 0", "NaN"),
+            ("inf - inf", "NaN"),
+        ] {
+            let chunk = compile(source).unwrap();
+            let mut vm = Vm::new(source, chunk).with_settings(settings);
+            assert_eq!(vm.eval().unwrap(), expected, "{source}");
+        }
+        let source = "inf * 2";
+        let chunk = compile(source).unwrap();
+        assert_eq!(Vm::new(source, chunk).eval().unwrap(), "inf");
+        for (source, span) in [
+            ("1e400", (0, 5)),
+            ("2 * 1e400i", (4, 6)),
+            ("1e400 m", (0, 7)),
+        ] {
+            let err = Vm::new(source, compile(source).unwrap())
+                .eval()
+                .unwrap_err();
+            assert_eq!(err.to_string(), "Result is infinite!", "{source}");
+            let label = err.labels().unwrap().next().unwrap();
+            assert_eq!((label.offset(), label.len()), span, "{source}");
+            let mut vm = Vm::new(source, compile(source).unwrap()).with_settings(settings);
+            assert!(vm.eval().unwrap().contains("inf"), "{source}");
+        }
+    }
+
+    #[test]
+    fn modulo_modes() {
+        for (modulo, expected) in [
+            (ModMode::Trunc, ["-1", "1", "-1", "1", "-1"]),
+            (ModMode::Floor, ["2", "-2", "-1", "-2", "-1"]),
+            (ModMode::Euclid, ["2", "1", "2", "1", "2"]),
+        ] {
+            let settings = Settings {
+                modulo,
+                ..Default::default()
+            };
+            let sources = ["-7 % 3", "7 % (-3)", "-7 % (-3)", "7 % -3", "-7 % -3"];
+            for (source, expected) in sources.iter().zip(expected) {
+                let chunk = compile(source).unwrap();
+                let mut vm = Vm::new(source, chunk).with_settings(settings);
+                assert_eq!(vm.eval().unwrap(), expected, "{source} in {modulo}");
+            }
+        }
+    }
+
+    #[test]
+    fn int_div_compilation() {
+        for (source, expected) in [
+            ("7 // 2", "3"),
+            ("-7 // 2", "-4"),
+            ("7.5 // -2", "-4"),
+            ("7 km // 2 m", "3500"),
+            ("rem(-7, 3) + mod(-7, 3)", "1"),
+        ] {
+            let chunk = compile(source).unwrap();
+            let mut vm = Vm::new(source, chunk);
+            assert_eq!(vm.eval().unwrap(), expected, "{source}");
+        }
+        let source = "1 // 0";
+        let chunk = compile(source).unwrap();
+        assert!(Vm::new(source, chunk).eval().is_err());
+    }
+
+    #[test]
+    fn implicit_mult_compilation() {
+        for (source, expected) in [
+            ("2(3 + 4)", "14"),
+            ("1/2pi", "0.15915494309189535"),
+            ("1/(2 * pi)", "0.15915494309189535"),
+            ("-2pi + tau", "0"),
+        ] {
+            let chunk = compile(source).unwrap();
+            let mut vm = Vm::new(source, chunk);
+            assert_eq!(vm.eval().unwrap(), expected, "{source}");
+        }
+    }
+
+    #[test]
+    fn list_compilation() {
+        let mut globals = Globals::new();
+        for (source, expected) in [
+            ("[1, 2, 3] * 2", "[2, 4, 6]"),
+            ("10 - [1, 2]", "[9, 8]"),
+            ("[1, 2] + [10, 20]", "[11, 22]"),
+            ("[[1, 2], [3, 4]] * [10, 100]", "[[10, 20], [300, 400]]"),
+            ("[1 km, 500 m] + 1 m", "[1.001 km, 501 m]"),
+            ("[1 km, 2 km] to m", "[1000 m, 2000 m]"),
+            ("-[1, 2]!", "[-1, -2]"),
+            ("$xs = [3, 1, 4, 1, 5]", "[3, 1, 4, 1, 5]"),
+            ("$xs[2] + $xs[-1]", "9"),
+            ("len($xs) + len([])", "5"),
+            ("sum($xs)", "14"),
+            ("max($xs, 10)", "10"),
+            ("median($xs)", "3"),
+            ("sqrt([1, 4, 9])", "[1, 2, 3]"),
+            ("round([1.25, 2.75], 1)", "[1.3, 2.8]"),
+            ("[[1, 2], [3]][0]", "[1, 2]"),
+        ] {
+            let chunk = compile(source).unwrap();
+            let mut vm = Vm::new(source, chunk).with_globals(globals);
+            assert_eq!(vm.eval().unwrap(), expected, "{source}");
+            globals = vm.into_globals();
+        }
+    }
+
+    #[test]
+    fn list_errors() {
+        for (source, span) in [
+            ("[1, 2] + [1, 2, 3]", (0, 6)),
+            ("[1, 2][2]", (7, 1)),
+            ("[1, 2][0.5]", (7, 3)),
+            ("3[0]", (0, 1)),
+            ("len(3)", (4, 1)),
+            ("sum([], [])", (0, 11)),
+            ("atan2([1, 2], [1])", (6, 6)),
+        ] {
+            let chunk = compile(source).unwrap();
+            let mut vm = Vm::new(source, chunk);
+            let err = vm.eval().unwrap_err();
+            let label = err.labels().unwrap().next().unwrap();
+            assert_eq!((label.offset(), label.len()), span, "{source}");
+        }
+    }
+
+    #[test]
+    fn matrix_compilation() {
+        let mut globals = Globals::new();
+        for (source, expected) in [
+            ("$a = [[2, 1], [1, 3]]", "[[2, 1], [1, 3]]"),
+            ("$a @ [[1, 0], [0, 2]]", "[[2, 2], [1, 6]]"),
+            ("$a @ [1, 1]", "[3, 4]"),
+            ("[1, 1] @ $a", "[3, 4]"),
+            ("[1, 2, 3] @ [4, 5, 6]", "32"),
+            (
+                "transpose([[1, 2, 3], [4, 5, 6]])",
+                "[[1, 4], [2, 5], [3, 6]]",
+            ),
+            ("det($a)", "5"),
+            ("det([[0, 1], [1, 0]])", "-1"),
+            ("round(inverse($a) * 10)", "[[6, -2], [-2, 4]]"),
+            ("round(linsolve($a, [3, 5]), 10)", "[0.8, 1.4]"),
+            ("$a * 2 - $a", "[[2, 1], [1, 3]]"),
+        ] {
+            let chunk = compile(source).unwrap();
+            let mut vm = Vm::new(source, chunk).with_globals(globals);
+            assert_eq!(vm.eval().unwrap(), expected, "{source}");
+            globals = vm.into_globals();
+        }
+    }
+
+    #[test]
+    fn matrix_errors() {
+        for (source, message, span) in [
+            (
+                "[[1, 2]] @ [[1, 2]]",
+                "Mismatched matrix dimensions!",
+                (0, 8),
+            ),
+            (
+                "[1, 2] @ [1, 2, 3]",
+                "Mismatched matrix dimensions!",
+                (0, 6),
+            ),
+            (
+                "det([[1, 2, 3], [4, 5, 6]])",
+                "Expected a square matrix!",
+                (4, 22),
+            ),
+            ("inverse([[1, 2], [2, 4]])", "Matrix is singular!", (8, 16)),
+            ("det([[1, 2], [3]])", "Expected a matrix!", (4, 13)),
+            ("transpose(2)", "Expected a matrix!", (10, 1)),
+            (
+                "linsolve([[1, 0], [0, 1]], [1, 2, 3])",
+                "Mismatched matrix dimensions!",
+                (9, 16),
+            ),
+        ] {
+            let chunk = compile(source).unwrap();
+            let mut vm = Vm::new(source, chunk);
+            let err = vm.eval().unwrap_err();
+            assert_eq!(err.to_string(), message, "{source}");
+            let label = err.labels().unwrap().next().unwrap();
+            assert_eq!((label.offset(), label.len()), span, "{source}");
+        }
+    }
+
+    #[test]
+    fn complex_compilation() {
+        let settings = Settings {
+            complex: true,
+            ..Default::default()
+        };
+        for (source, expected) in [
+            ("3 + 4i", "3 + 4i"),
+            ("(1 + 2i) * (3 - 1i)", "5 + 5i"),
+            ("2i * 2i", "-4"),
+            ("(4 + 2i) / 2i", "1 - 2i"),
+            ("abs(3 + 4i) + re(1 - 1i) + im(2i)", "8"),
+            ("conj(1 + 1i)", "1 - 1i"),
+            ("arg(1i) * 2 - pi", "0"),
+            ("sqrt(-4)", "2i"),
+            ("sqrt(-1) * sqrt(-1)", "-1"),
+            ("ln(-1)", "3.141592653589793i"),
+            ("[1, 2] * 1i", "[1i, 2i]"),
+            ("(3 + 4i) * 1 kV to V", "3000 + 4000i V"),
+        ] {
+            let chunk = compile(source).unwrap();
+            let mut vm = Vm::new(source, chunk).with_settings(settings);
+            assert_eq!(vm.eval().unwrap(), expected, "{source}");
+        }
+    }
+
+    #[test]
+    fn complex_errors() {
+        for (source, message, span) in [
+            ("sqrt(-1)", "Math domain error!", (0, 8)),
+            ("floor(1 + 1i)", "Expected a real number!", (6, 6)),
+            ("2i % 2", "Expected a real number!", (0, 2)),
+            ("2i & 1", "Expected a real number!", (0, 2)),
+            ("1 / (0i)", "Division by zero!", (0, 7)),
+        ] {
+            let chunk = compile(source).unwrap();
+            let mut vm = Vm::new(source, chunk);
+            let err = vm.eval().unwrap_err();
+            assert_eq!(err.to_string(), message, "{source}");
+            let label = err.labels().unwrap().next().unwrap();
+            assert_eq!((label.offset(), label.len()), span, "{source}");
+        }
+    }
+
+    #[test]
+    fn statement_compilation() {
+        for (source, echo, expected) in [
+            ("1 + 2; 3 * 4", Echo::Last, "12"),
+            ("1 + 2; 3 * 4", Echo::All, "3\n12"),
+            (
+                "$r = 2\npi * $r * $r; $r * 2\n",
+                Echo::All,
+                "12.566370614359172\n4",
+            ),
+            ("$a = 1; $b = $a + 1", Echo::All, "2"),
+            ("[1,\n 2]\n", Echo::All, "[1, 2]"),
+        ] {
+            let chunk = compile(source).unwrap();
+            let settings = Settings {
+                echo,
+                ..Default::default()
+            };
+            let mut vm = Vm::new(source, chunk).with_settings(settings);
+            assert_eq!(vm.eval().unwrap(), expected, "{source:?}");
+        }
+        for source in ["1 + 2 3", "(1 + 2) 3", "1; 2 )", "sqrt(4) sqrt(9)", ""] {
+            assert!(compile(source).is_err(), "{source:?}");
+        }
+    }
+
+    #[test]
+    fn trailing_input() {
+        for (source, span) in [
+            ("1 + 2 )", (6, 1)),
+            ("3 4", (2, 1)),
+            ("sqrt(4) ] 1", (8, 3)),
+        ] {
+            let Err(err) = compile(source) else {
+                panic!("{source} should not compile");
+            };
+            assert_eq!(err.to_string(), "Unexpected trailing input!", "{source}");
+            let label = err.labels().unwrap().next().unwrap();
+            assert_eq!((label.offset(), label.len()), span, "{source}");
+        }
+    }
+
+    // Valid expressions followed by something that cannot continue them must be
+    // rejected rather than evaluated up to the garbage
+    #[test]
+    fn malformed_inputs_are_rejected() {
+        let valid = [
+            "1",
+            "1 + 2",
+            "(1 + 2)",
+            "2 km",
+            "3 m to cm",
+            "sqrt(4)",
+            "[1, 2][0]",
+            "$x = 4",
+            "5!",
+            "(20%)",
+            "pi",
+            "\"text\"",
+            "2i",
+        ];
+        let garbage = [
+            ")", "]", ",", "=", "3", "4.5", "\"x\"", "$y", "!(", "1 2", "to",
+        ];
+        for expression in valid {
+            assert!(compile(expression).is_ok(), "{expression}");
+            for junk in garbage {
+                let source = format!("{expression} {junk}");
+                assert!(compile(&source).is_err(), "{source} was accepted");
+            }
+        }
+    }
+
+    // Random token soup must produce diagnostics, never panics
+    #[test]
+    fn random_inputs_never_panic() {
+        let tokens = [
+            "1", "2.5", "0x1f", "(", ")", "[", "]", "+", "-", "*", "/", "//", "%", "!", "@", "^",
+            "&", "|", "~", "<<", ">>", "xor", "to", "of", "=", "$x", ",", ";", "\n", "m", "km",
+            "s", "pi", "sqrt", "sum", "len", "i", "\"a\"", "\"", "1e", "#", "?", "..", "..=", "{",
+            "}", "for", "in", "x", "->", "map", "$f", "L", "127", "m^100",
+        ];
+        for source in [
+            "1 L^50",
+            "1 m^100 * 1 m^100",
+            "$x = 1 m^127; $x * $x",
+            "1 m^-127 / 1 m",
+            "1 m^100*m^100",
+        ] {
+            if let Ok(chunk) = compile(source) {
+                let _ = Vm::new(source, chunk).eval();
+            }
+        }
+        let mut state: u64 = 0x2545_f491_4f6c_dd1d;
+        let mut next = || {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state as usize
+        };
+        for _ in 0..5000 {
+            let len = next() % 8 + 1;
+            let source: Vec<_> = (0..len).map(|_| tokens[next() % tokens.len()]).collect();
+            let source = source.join(" ");
+            if let Ok(chunk) = compile(&source) {
+                let _ = Vm::new(&source, chunk).eval();
+            }
+        }
+    }
+
+    #[test]
+    fn string_compilation() {
+        let mut globals = Globals::new();
+        for (source, expected) in [
+            (r#""total: " + "5""#, "total: 5"),
+            (r#"$name = "nex""#, "nex"),
+            (r#"concat("v", 2, " of ", $name)"#, "v2 of nex"),
+            (r#"format("{:.2} USD", 10 / 3)"#, "3.33 USD"),
+            (r#"format("{} is {}", $name, [1, 2 m])"#, "nex is [1, 2 m]"),
+            (r#"format("{:.1} {{x}}", 2 km)"#, "2.0 km {x}"),
+            (r#"len("héllo")"#, "5"),
+            (r#"["a", 1]"#, r#"["a", 1]"#),
+        ] {
+            let chunk = compile(source).unwrap();
+            let mut vm = Vm::new(source, chunk).with_globals(globals);
+            assert_eq!(vm.eval().unwrap(), expected, "{source}");
+            globals = vm.into_globals();
+        }
+    }
+
+    #[test]
+    fn string_errors() {
+        for (source, message, span) in [
+            (r#""a" - 1"#, "Mismatched types!", (0, 3)),
+            (r#"1 + "a""#, "Mismatched types!", (0, 1)),
+            (r#"sqrt("4")"#, "Expected a number!", (5, 3)),
+            (r#"-"a""#, "Expected a number!", (1, 3)),
+            (r#"sum([1, "2"])"#, "Expected a number!", (4, 8)),
+            (r#"format("{} {}", 1)"#, "Invalid format string!", (7, 7)),
+            (r#"format("{}", 1, 2)"#, "Invalid format string!", (7, 4)),
+            (r#"format(1)"#, "Invalid format string!", (7, 1)),
+        ] {
+            let chunk = compile(source).unwrap();
+            let mut vm = Vm::new(source, chunk);
+            let err = vm.eval().unwrap_err();
+            assert_eq!(err.to_string(), message, "{source}");
+            let label = err.labels().unwrap().next().unwrap();
+            assert_eq!((label.offset(), label.len()), span, "{source}");
+        }
+    }
+
+    #[test]
+    fn loop_compilation() {
+        for (source, echo, expected) in [
+            ("1..5", Echo::Last, "[1, 2, 3, 4]"),
+            ("1..=5", Echo::Last, "[1, 2, 3, 4, 5]"),
+            ("0.5..3", Echo::Last, "[0.5, 1.5, 2.5]"),
+            ("5..1", Echo::Last, "[]"),
+            ("sum(1..=100)", Echo::Last, "5050"),
+            ("for i in 1..=4 { $i * $i }", Echo::Last, "[1, 4, 9, 16]"),
+            ("sum(for i in 1..=100 { $i * $i })", Echo::Last, "338350"),
+            (
+                "$total = 0; for i in 1..=12 { $total = $total + $i }; $total",
+                Echo::Last,
+                "78",
+            ),
+            // The loop variable keeps its last value and shadows an earlier one
+            ("$i = 10; for i in [1, 2, 3] { $i }; $i", Echo::Last, "3"),
+            ("$i = 10; for i in 1..1 { $i }; $i", Echo::Last, "10"),
+            (
+                "$n = 3; for i in 1..=$n { $n = $n + 1; $i }; $n",
+                Echo::Last,
+                "6",
+            ),
+            (
+                "for i in 1..=2 { for j in 1..=2 { $i * 10 + $j } }",
+                Echo::Last,
+                "[[11, 12], [21, 22]]",
+            ),
+            (
+                "$b = 100\nfor month in 1..=3 {\n  $b = $b * 1.1\n  $month\n}",
+                Echo::All,
+                "[1, 2, 3]",
+            ),
+            ("for i in 1..=2 { $i; -$i }", Echo::All, "1\n2\n[-1, -2]"),
+        ] {
+            let chunk = compile(source).unwrap();
+            let settings = Settings {
+                echo,
+                ..Default::default()
+            };
+            let mut vm = Vm::new(source, chunk).with_settings(settings);
+            assert_eq!(vm.eval().unwrap(), expected, "{source:?}");
+        }
+    }
+
+    #[test]
+    fn loop_errors() {
+        for (source, message, span) in [
+            ("for i in 3 { $i }", "Expected a list!", (9, 1)),
+            (r#"1..="a""#, "Expected a number!", (4, 3)),
+            ("1 m..2", "Expected a plain number!", (0, 3)),
+            ("0..1e9", "Range is too long!", (0, 6)),
+            ("for i in 1..3 { $j }", "Undefined variable!", (16, 2)),
+        ] {
+            let chunk = compile(source).unwrap();
+            let mut vm = Vm::new(source, chunk);
+            let err = vm.eval().unwrap_err();
+            assert_eq!(err.to_string(), message, "{source}");
+            let label = err.labels().unwrap().next().unwrap();
+            assert_eq!((label.offset(), label.len()), span, "{source}");
+        }
+        let source = "for i in 1..=1000 { for j in 1..=1000 { $j } }";
+        let mut vm = Vm::new(source, compile(source).unwrap()).with_budget(10_000);
+        let err = vm.eval().unwrap_err();
+        assert_eq!(err.to_string(), "Instruction budget exhausted!");
+        let source = "sum(for i in 1..=10 { $i })";
+        let mut vm = Vm::new(source, compile(source).unwrap()).with_budget(100);
+        assert_eq!(vm.eval().unwrap(), "55");
+    }
+
+    #[test]
+    fn lambda_compilation() {
+        for (source, expected) in [
+            ("$f = x -> $x * 2; $f(21)", "42"),
+            ("$f = x -> $x * 2; $f", "x -> $x * 2"),
+            ("[x -> $x, (a, b) -> $a]", "[x -> $x, (a, b) -> $a]"),
+            ("(() -> 7)()", "7"),
+            ("map(1..=4, x -> $x * $x)", "[1, 4, 9, 16]"),
+            ("map([1, 2, 3], x -> $x^2)", "[1, 4, 9]"),
+            ("sum(map(1..=20000, x -> $x))", "200010000"),
+            ("filter(1..=10, x -> $x % 3)", "[1, 2, 4, 5, 7, 8, 10]"),
+            ("reduce(1..=5, (a, b) -> $a * $b)", "120"),
+            ("reduce([], (a, b) -> $a + $b, 0)", "0"),
+            (r#"reduce(["a", "b"], (a, b) -> $b + $a)"#, "ba"),
+            ("sort_by([3, -5, 1, -2], x -> abs($x))", "[1, -2, 3, -5]"),
+            ("sort_by([[2, 1], [1, 9]], p -> $p[0])", "[[1, 9], [2, 1]]"),
+            ("compose(x -> $x + 1, x -> $x * 10)(2)", "21"),
+            ("compose(x -> $x + 1, (a, b) -> $a - $b)(5, 2)", "4"),
+            ("compose(x -> $x)(3)", "3"),
+            // Captured when the lambda is made, later assignments do not change it
+            ("$k = 3; $f = x -> $x * $k; $k = 10; $f(2)", "6"),
+            (
+                "$make = k -> (y -> $y * $k); $triple = $make(3); $triple(5)",
+                "15",
+            ),
+            (
+                "$make = k -> (y -> $y * $k); map([1, 2], $make(4))",
+                "[4, 8]",
+            ),
+            // Variables set after the lambda is made are looked up at the call
+            ("$f = x -> $x + $later; $later = 1; $f(1)", "2"),
+            // A call leaves the variables of its caller alone
+            ("$x = 5; (x -> $x * 2)(1); $x", "5"),
+            ("$t = 1; (x -> ($t = $x))(9); $t", "1"),
+            ("$fs = [x -> $x + 1, x -> $x * 3]; $fs[1](4)", "12"),
+            // `x` belongs to the inner lambda, so the outer one does not capture it
+            // and `$g` sees the `$x` of the time of the call
+            (
+                "$g = () -> $x; $x = 1; $f = () -> [map([0], x -> $x), $g()]; $x = 2; $f()",
+                "[[0], 2]",
+            ),
+            ("map([1, 2], x -> for i in 1..=$x { $i })", "[[1], [1, 2]]"),
+        ] {
+            let chunk = compile(source).unwrap();
+            let mut vm = Vm::new(source, chunk);
+            assert_eq!(vm.eval().unwrap(), expected, "{source:?}");
+        }
+    }
+
+    #[test]
+    fn numerical_methods() {
+        for (source, expected) in [
+            ("solve($x * $x - 2, x, 1)", "1.414213562373095"),
+            ("solve(sin($x), x, 3)", "3.141592653589793"),
+            ("$a = 2; solve($x - $a, x, 0)", "2"),
+            ("integrate($x * $x, x, 0, 3)", "9"),
+            ("integrate(1, x, 0, 3)", "3"),
+            ("round(integrate(sin($t), t, 0, pi), 10)", "2"),
+            ("diff($x * $x, x, 3)", "6"),
+            ("round(diff(exp($x), x, 0), 10)", "1"),
+            // The variable is local to the expression
+            ("$x = 5; diff($x * $x, x, 1); $x", "5"),
+        ] {
+            let chunk = compile(source).unwrap();
+            let mut vm = Vm::new(source, chunk);
+            assert_eq!(vm.eval().unwrap(), expected, "{source:?}");
+        }
+    }
+
+    #[test]
+    fn lambda_errors() {
+        for (source, message, span) in [
+            ("$f = 2; $f(1)", "Expected a function!", (8, 2)),
+            ("map(1, x -> $x)", "Expected a list!", (4, 1)),
+            ("map([1], 2)", "Expected a function!", (9, 1)),
+            (
+                "$f = (a, b) -> $a; $f(1)",
+                "Function expects 2 arguments but got 1!",
+                (19, 5),
+            ),
+            (
+                "map([1], (a, b) -> $a)",
+                "Function expects 2 arguments but got 1!",
+                (9, 12),
+            ),
+            (
+                "reduce([], (a, b) -> $a)",
+                "reduce needs at least one value!",
+                (7, 2),
+            ),
+            (r#"filter([1], x -> "a")"#, "Expected a number!", (12, 8)),
+            ("(x -> $x) + 1", "Mismatched types!", (1, 7)),
+            ("-(x -> $x)", "Expected a number!", (2, 7)),
+            ("map([1], x -> $y)", "Error in function x -> $y!", (9, 7)),
+            ("$f = x -> $f($x); $f(1)", "Too many nested calls!", (10, 6)),
+            (
+                "solve($x * $x + 1, x, 0)",
+                "solve did not converge!",
+                (0, 24),
+            ),
+            ("solve(1 / $x, x, 0.5)", "solve did not converge!", (0, 21)),
+            (
+                "integrate(1 / $x, x, 0, 1)",
+                "Error in function 1 / $x!",
+                (10, 6),
+            ),
+            (
+                "integrate($x, x, 0, inf)",
+                "integrate did not converge!",
+                (0, 24),
+            ),
+            ("diff(cbrt($x), x, 0)", "diff did not converge!", (0, 20)),
+            (r#"solve("a", x, 1)"#, "Expected a number!", (6, 3)),
+            ("solve($x, x, [1])", "Expected a number!", (13, 3)),
+        ] {
+            let chunk = compile(source).unwrap();
+            let mut vm = Vm::new(source, chunk);
+            let err = vm.eval().unwrap_err();
+            assert_eq!(err.to_string(), message, "{source}");
+            let label = err.labels().unwrap().next().unwrap();
+            assert_eq!((label.offset(), label.len()), span, "{source}");
+        }
+        let source = "map([1], x -> $y)";
+        let err = Vm::new(source, compile(source).unwrap())
+            .eval()
+            .unwrap_err();
+        let related = err.related().unwrap().next().unwrap();
+        assert_eq!(related.to_string(), "Undefined variable!");
+        let source = "$f = x -> $f($x); $f(1)";
+        let mut vm = Vm::new(source, compile(source).unwrap()).with_budget(100);
+        let err = vm.eval().unwrap_err();
+        assert_eq!(err.to_string(), "Instruction budget exhausted!");
+    }
+
+    #[test]
+    fn factorial_compilation() {
+        for (source, expected) in [
+            ("5!", "120"),
+            ("-3!", "-6"),
+            ("30!", "265252859812191058636308480000000"),
+            ("30! + 1", "265252859812191058636308480000001"),
+            ("25! * 2 - 25!", "15511210043330985984000000"),
+            ("3!! / 2", "360"),
+            ("nCr(52, 5)", "2598960"),
+            ("choose(60, 30)", "118264581564861424"),
+            ("nPr(5, 2)", "20"),
+            ("gamma(5)", "24"),
+        ] {
+            let chunk = compile(source).unwrap();
+            let mut vm = Vm::new(source, chunk);
+            assert_eq!(vm.eval().unwrap(), expected, "{source}");
+        }
+    }
+
+    #[test]
+    fn factorial_errors() {
+        for (source, span) in [("2.5!", (0, 3)), ("(1 - 3)!", (1, 5)), ("2 m!", (0, 3))] {
+            let chunk = compile(source).unwrap();
+            let mut vm = Vm::new(source, chunk);
+            let err = vm.eval().unwrap_err();
+            let label = err.labels().unwrap().next().unwrap();
+            assert_eq!((label.offset(), label.len()), span, "{source}");
+        }
+        for source in ["nCr(5, -1)", "nPr(2.5, 1)", "gamma(0)", "100000!"] {
+            let chunk = compile(source).unwrap();
+            assert!(Vm::new(source, chunk).eval().is_err(), "{source}");
+        }
+    }
+
     #[test]
     fn pos_num_compilation() {
         let source = "+(3 + 2)";
-        let chunk = compile(&source).unwrap();
+        let chunk = compile(source).unwrap();
         let mut vm = Vm::new(source, chunk);
         let result = vm.eval().unwrap();
         assert_eq!(result, "5");
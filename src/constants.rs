@@ -0,0 +1,39 @@
+use std::f64::consts;
+
+// name, value, description
+pub const CONSTANTS: &[(&str, f64, &str)] = &[
+    (
+        "pi",
+        consts::PI,
+        "ratio of a circle's circumference to its diameter",
+    ),
+    ("e", consts::E, "base of the natural logarithm"),
+    (
+        "tau",
+        consts::TAU,
+        "ratio of a circle's circumference to its radius",
+    ),
+    ("phi", 1.618_033_988_749_895, "the golden ratio"),
+    ("inf", f64::INFINITY, "positive infinity"),
+    ("nan", f64::NAN, "not a number"),
+];
+
+pub fn lookup(name: &str) -> Option<f64> {
+    CONSTANTS
+        .iter()
+        .find(|(constant, _, _)| *constant == name)
+        .map(|(_, value, _)| *value)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn lookup_constants() {
+        assert_eq!(lookup("pi"), Some(consts::PI));
+        assert_eq!(lookup("tau"), Some(2. * consts::PI));
+        assert!(lookup("nan").unwrap().is_nan());
+        assert_eq!(lookup("pie"), None);
+    }
+}
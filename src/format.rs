@@ -0,0 +1,605 @@
+use crate::number::Number;
+use crate::stack::Value;
+use crate::units::Unit;
+use num_bigint::BigInt;
+use num_traits::Signed;
+use std::fmt;
+use std::str::FromStr;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Base {
+    #[default]
+    Dec,
+    Hex,
+    Bin,
+    Oct,
+}
+
+impl FromStr for Base {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "dec" => Ok(Base::Dec),
+            "hex" => Ok(Base::Hex),
+            "bin" => Ok(Base::Bin),
+            "oct" => Ok(Base::Oct),
+            _ => Err(format!("Unknown base {s}, expected one of hex|bin|oct|dec")),
+        }
+    }
+}
+
+impl fmt::Display for Base {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Base::Dec => write!(f, "dec"),
+            Base::Hex => write!(f, "hex"),
+            Base::Bin => write!(f, "bin"),
+            Base::Oct => write!(f, "oct"),
+        }
+    }
+}
+
+/// How decimal numbers are written out.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Notation {
+    /// The shortest text that reads back as the same number.
+    #[default]
+    Auto,
+    /// A fixed number of decimals.
+    Fixed(usize),
+    /// A number of significant figures, in scientific notation when very large
+    /// or small.
+    Sig(usize),
+    /// Scientific notation, with the given significant figures or as many as needed.
+    Sci(Option<usize>),
+    /// Scientific notation with an exponent that is a multiple of three.
+    Eng(Option<usize>),
+}
+
+impl Notation {
+    /// Parses a notation name followed by its digit count, e.g. `fixed 2`.
+    pub fn parse(name: &str, digits: Option<&str>) -> Result<Self, String> {
+        let digits = digits
+            .map(|digits| match digits.parse() {
+                Ok(digits) if digits <= MAX_DIGITS => Ok(digits),
+                _ => Err(format!(
+                    "Expected at most {MAX_DIGITS} digits, got {digits}"
+                )),
+            })
+            .transpose()?;
+        let needs_digits = || digits.ok_or(format!("{name} expects a number of digits"));
+        match name {
+            "auto" => Ok(Notation::Auto),
+            "fixed" => Ok(Notation::Fixed(needs_digits()?)),
+            "sig" => Ok(Notation::Sig(needs_digits()?.max(1))),
+            "sci" => Ok(Notation::Sci(digits.map(|digits| digits.max(1)))),
+            "eng" => Ok(Notation::Eng(digits.map(|digits| digits.max(1)))),
+            _ => Err(format!(
+                "Unknown notation {name}, expected one of auto|fixed|sig|sci|eng"
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Notation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Notation::Auto => write!(f, "auto"),
+            Notation::Fixed(digits) => write!(f, "fixed {digits}"),
+            Notation::Sig(digits) => write!(f, "sig {digits}"),
+            Notation::Sci(Some(digits)) => write!(f, "sci {digits}"),
+            Notation::Sci(None) => write!(f, "sci"),
+            Notation::Eng(Some(digits)) => write!(f, "eng {digits}"),
+            Notation::Eng(None) => write!(f, "eng"),
+        }
+    }
+}
+
+// Beyond this f64 has no more digits to show
+const MAX_DIGITS: usize = 17;
+
+/// The character between the whole and fractional part of a number. Thousands
+/// are grouped with the other one, so `1,234.5` or `1.234,5`.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Mark {
+    #[default]
+    Point,
+    Comma,
+}
+
+impl Mark {
+    fn decimal(self) -> char {
+        match self {
+            Mark::Point => '.',
+            Mark::Comma => ',',
+        }
+    }
+
+    fn thousands(self) -> char {
+        match self {
+            Mark::Point => ',',
+            Mark::Comma => '.',
+        }
+    }
+
+    // Items of a list are split by `;` when the comma is the decimal mark, so
+    // `[0,3; 1]` cannot be read as three numbers
+    fn separator(self) -> &'static str {
+        match self {
+            Mark::Point => ", ",
+            Mark::Comma => "; ",
+        }
+    }
+}
+
+impl FromStr for Mark {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "point" | "." => Ok(Mark::Point),
+            "comma" | "," => Ok(Mark::Comma),
+            _ => Err(format!(
+                "Unknown decimal mark {s}, expected one of point|comma"
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Mark {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Mark::Point => write!(f, "point"),
+            Mark::Comma => write!(f, "comma"),
+        }
+    }
+}
+
+/// How results are printed in decimal.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Style {
+    pub notation: Notation,
+    /// Group the digits of the whole part in thousands.
+    pub group: bool,
+    pub mark: Mark,
+}
+
+impl fmt::Display for Style {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let group = if self.group { "on" } else { "off" };
+        write!(f, "{}, group {}, mark {}", self.notation, group, self.mark)
+    }
+}
+
+// Only whole numbers have a meaningful representation in another base, anything
+// else falls back to decimal
+pub fn format_number(num: &Number, base: Base, style: Style) -> String {
+    let num = match num {
+        Number::Int(num) if base == Base::Dec => return format_exact(num, style),
+        Number::Int(num) => return format_int(num, base),
+        Number::Complex(num) => {
+            let re = format_float(num.re, style);
+            let im = format_float(num.im.abs(), style);
+            return match (num.re == 0.0, num.im < 0.0) {
+                (true, false) => format!("{im}i"),
+                (true, true) => format!("-{im}i"),
+                (false, negative) => format!("{re} {} {im}i", if negative { '-' } else { '+' }),
+            };
+        }
+        Number::Float(num) => *num,
+    };
+    if base == Base::Dec || num.fract() != 0.0 || num.abs() > u64::MAX as f64 {
+        return format_float(num, style);
+    }
+    let sign = if num < 0.0 { "-" } else { "" };
+    let magnitude = num.abs() as u64;
+    match base {
+        Base::Hex => format!("{sign}{magnitude:#x}"),
+        Base::Bin => format!("{sign}{magnitude:#b}"),
+        Base::Oct => format!("{sign}{magnitude:#o}"),
+        Base::Dec => unreachable!(),
+    }
+}
+
+fn format_float(num: f64, style: Style) -> String {
+    if !num.is_finite() {
+        return num.to_string();
+    }
+    let text = match style.notation {
+        Notation::Auto => num.to_string(),
+        Notation::Fixed(digits) => format!("{num:.digits$}"),
+        Notation::Sig(digits) => positional(significant(num, Some(digits))),
+        Notation::Sci(digits) => scientific(significant(num, digits)),
+        Notation::Eng(digits) => engineering(significant(num, digits)),
+    };
+    localize(&text, style)
+}
+
+// Exact integers keep every digit unless asked for significant figures
+fn format_exact(num: &BigInt, style: Style) -> String {
+    let text = match style.notation {
+        Notation::Auto => num.to_string(),
+        Notation::Fixed(0) => num.to_string(),
+        Notation::Fixed(digits) => format!("{num}.{}", "0".repeat(digits)),
+        Notation::Sig(digits) => positional(exact_digits(num, Some(digits))),
+        Notation::Sci(digits) => scientific(exact_digits(num, digits)),
+        Notation::Eng(digits) => engineering(exact_digits(num, digits)),
+    };
+    localize(&text, style)
+}
+
+// A number as its sign, its significant digits and the power of ten of the first
+// digit, so 0.0125 is `(false, "125", -2)`
+struct Digits(bool, String, i64);
+
+fn significant(num: f64, digits: Option<usize>) -> Digits {
+    let text = match digits {
+        Some(digits) => format!("{:.*e}", digits.max(1) - 1, num.abs()),
+        None => format!("{:e}", num.abs()),
+    };
+    let (mantissa, exponent) = text.split_once('e').unwrap();
+    Digits(
+        num < 0.0,
+        mantissa.replace('.', ""),
+        exponent.parse().unwrap(),
+    )
+}
+
+fn exact_digits(num: &BigInt, digits: Option<usize>) -> Digits {
+    let mut text = num.magnitude().to_string();
+    let mut exponent = text.len() as i64 - 1;
+    match digits.map(|digits| digits.max(1)) {
+        Some(digits) if digits < text.len() => {
+            let round_up = text.as_bytes()[digits] >= b'5';
+            text.truncate(digits);
+            if round_up {
+                // Carry through trailing nines, 999 rounds up to 1000
+                let kept = text.trim_end_matches('9').len();
+                text.truncate(kept);
+                match text.pop() {
+                    Some(last) => text.push((last as u8 + 1) as char),
+                    None => {
+                        text.push('1');
+                        exponent += 1;
+                    }
+                }
+                text.extend(std::iter::repeat_n('0', digits - text.len()));
+            }
+        }
+        Some(digits) => text.extend(std::iter::repeat_n('0', digits - text.len())),
+        None => text.truncate(text.trim_end_matches('0').len().max(1)),
+    }
+    Digits(num.is_negative(), text, exponent)
+}
+
+// Plain decimal notation, switching to scientific for very large or small numbers
+fn positional(Digits(negative, digits, exponent): Digits) -> String {
+    if !(-7..21).contains(&exponent) {
+        return scientific(Digits(negative, digits, exponent));
+    }
+    let sign = if negative { "-" } else { "" };
+    if exponent < 0 {
+        let zeros = "0".repeat((-exponent - 1) as usize);
+        return format!("{sign}0.{zeros}{digits}");
+    }
+    let whole = exponent as usize + 1;
+    split_at(sign, digits, whole)
+}
+
+fn scientific(Digits(negative, digits, exponent): Digits) -> String {
+    let sign = if negative { "-" } else { "" };
+    format!("{}e{exponent}", split_at(sign, digits, 1))
+}
+
+fn engineering(Digits(negative, digits, exponent): Digits) -> String {
+    let shift = exponent.rem_euclid(3);
+    let sign = if negative { "-" } else { "" };
+    let mantissa = split_at(sign, digits, shift as usize + 1);
+    format!("{mantissa}e{}", exponent - shift)
+}
+
+// Puts the decimal point after `whole` digits, padding with zeros if needed
+fn split_at(sign: &str, mut digits: String, whole: usize) -> String {
+    if digits.len() <= whole {
+        digits.extend(std::iter::repeat_n('0', whole - digits.len()));
+        return format!("{sign}{digits}");
+    }
+    let (whole, fraction) = digits.split_at(whole);
+    format!("{sign}{whole}.{fraction}")
+}
+
+// Swaps in the decimal mark and groups the whole part in thousands
+fn localize(text: &str, style: Style) -> String {
+    let (sign, text) = match text.strip_prefix('-') {
+        Some(text) => ("-", text),
+        None => ("", text),
+    };
+    let end = text
+        .find(|ch: char| !ch.is_ascii_digit())
+        .unwrap_or(text.len());
+    let (whole, rest) = text.split_at(end);
+    let mut out = sign.to_string();
+    for (i, digit) in whole.chars().enumerate() {
+        if style.group && i > 0 && (whole.len() - i) % 3 == 0 {
+            out.push(style.mark.thousands());
+        }
+        out.push(digit);
+    }
+    match rest.strip_prefix('.') {
+        Some(rest) => {
+            out.push(style.mark.decimal());
+            out.push_str(rest);
+        }
+        None => out.push_str(rest),
+    }
+    out
+}
+
+// Strings print as they are, but are quoted inside lists so `["a, b"]` stays
+// distinguishable from `["a", "b"]`
+pub fn format_value(value: &Value, base: Base, style: Style) -> String {
+    match value {
+        Value::Number(num, unit) => with_unit(format_number(num, base, style), unit),
+        Value::Str(text) => text.clone(),
+        Value::List(items) => {
+            let items: Vec<_> = items
+                .iter()
+                .map(|item| match item {
+                    Value::Str(text) => format!("{text:?}"),
+                    item => format_value(item, base, style),
+                })
+                .collect();
+            format!("[{}]", items.join(style.mark.separator()))
+        }
+        Value::Function(function) => function.to_string(),
+    }
+}
+
+/// Fills the `{}` and `{:.N}` placeholders of `template` with `args` in order,
+/// `{{` and `}}` stand for literal braces. The error explains what is wrong with
+/// the template.
+pub fn render(template: &str, args: &[Value], base: Base, style: Style) -> Result<String, String> {
+    let mut out = String::new();
+    let mut args = args.iter();
+    let mut chars = template.chars();
+    while let Some(ch) = chars.next() {
+        match ch {
+            '{' if chars.as_str().starts_with('{') => {
+                chars.next();
+                out.push('{');
+            }
+            '}' if chars.as_str().starts_with('}') => {
+                chars.next();
+                out.push('}');
+            }
+            '{' => {
+                let (spec, rest) = chars
+                    .as_str()
+                    .split_once('}')
+                    .ok_or("unclosed `{` placeholder")?;
+                chars = rest.chars();
+                let arg = args.next().ok_or("more placeholders than values")?;
+                let notation = match spec {
+                    "" => style.notation,
+                    _ => spec
+                        .strip_prefix(":.")
+                        .and_then(|digits| digits.parse().ok())
+                        .map(Notation::Fixed)
+                        .ok_or(format!("unknown placeholder `{{{spec}}}`"))?,
+                };
+                out.push_str(&format_value(arg, base, Style { notation, ..style }));
+            }
+            '}' => return Err("unmatched `}`, write `}}` for a literal brace".into()),
+            ch => out.push(ch),
+        }
+    }
+    match args.next() {
+        Some(_) => Err("more values than placeholders".into()),
+        None => Ok(out),
+    }
+}
+
+fn with_unit(num: String, unit: &Unit) -> String {
+    if unit.is_empty() {
+        num
+    } else {
+        format!("{} {}", num, unit)
+    }
+}
+
+fn format_int(num: &BigInt, base: Base) -> String {
+    let sign = if num.is_negative() { "-" } else { "" };
+    let magnitude = num.magnitude();
+    match base {
+        Base::Dec => num.to_string(),
+        Base::Hex => format!("{sign}{magnitude:#x}"),
+        Base::Bin => format!("{sign}{magnitude:#b}"),
+        Base::Oct => format!("{sign}{magnitude:#o}"),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn format_bases() {
+        assert_eq!(
+            format_number(&255.0.into(), Base::Hex, Style::default()),
+            "0xff"
+        );
+        assert_eq!(
+            format_number(&10.0.into(), Base::Bin, Style::default()),
+            "0b1010"
+        );
+        assert_eq!(
+            format_number(&493.0.into(), Base::Oct, Style::default()),
+            "0o755"
+        );
+        assert_eq!(
+            format_number(&(-255.0).into(), Base::Hex, Style::default()),
+            "-0xff"
+        );
+        assert_eq!(
+            format_number(&255.0.into(), Base::Dec, Style::default()),
+            "255"
+        );
+    }
+
+    #[test]
+    fn format_fractions_in_decimal() {
+        assert_eq!(
+            format_number(&2.5.into(), Base::Hex, Style::default()),
+            "2.5"
+        );
+        assert_eq!(
+            format_number(&f64::INFINITY.into(), Base::Bin, Style::default()),
+            "inf"
+        );
+    }
+
+    #[test]
+    fn format_lists() {
+        let list = Value::List(vec![1.0.into(), Value::List(vec![2.5.into()]), 3.0.into()]);
+        assert_eq!(
+            format_value(&list, Base::Dec, Style::default()),
+            "[1, [2.5], 3]"
+        );
+        assert_eq!(
+            format_value(&Value::List(vec![]), Base::Hex, Style::default()),
+            "[]"
+        );
+    }
+
+    #[test]
+    fn format_templates() {
+        let args = [2.0.into(), Value::Str("USD".into())];
+        assert_eq!(
+            render("{:.2} {} {{total}}", &args, Base::Dec, Style::default()).unwrap(),
+            "2.00 USD {total}"
+        );
+        assert_eq!(
+            render(
+                "{:.1}",
+                &[Value::List(vec![1.25.into(), 2.0.into()])],
+                Base::Dec,
+                Style::default()
+            )
+            .unwrap(),
+            "[1.2, 2.0]"
+        );
+        assert_eq!(
+            render("{} {}", &args[..1], Base::Dec, Style::default()).unwrap_err(),
+            "more placeholders than values"
+        );
+        assert_eq!(
+            render("{}", &args, Base::Dec, Style::default()).unwrap_err(),
+            "more values than placeholders"
+        );
+        assert!(render("{:x}", &args[..1], Base::Dec, Style::default()).is_err());
+        assert!(render("{", &args[..1], Base::Dec, Style::default()).is_err());
+    }
+
+    #[test]
+    fn format_strings() {
+        let list = Value::List(vec![Value::Str("a, b".into()), 1.0.into()]);
+        assert_eq!(
+            format_value(&list, Base::Dec, Style::default()),
+            r#"["a, b", 1]"#
+        );
+        assert_eq!(
+            format_value(&Value::Str("hi".into()), Base::Dec, Style::default()),
+            "hi"
+        );
+    }
+
+    #[test]
+    fn format_notations() {
+        let style = |notation| Style {
+            notation,
+            ..Style::default()
+        };
+        for (num, notation, expected) in [
+            (0.1 + 0.2, Notation::Auto, "0.30000000000000004"),
+            (0.1 + 0.2, Notation::Fixed(2), "0.30"),
+            (0.1 + 0.2, Notation::Sig(3), "0.300"),
+            (123456.0, Notation::Sig(2), "120000"),
+            (-0.000123456, Notation::Sig(2), "-0.00012"),
+            (1e21, Notation::Sig(2), "1.0e21"),
+            (1234.5, Notation::Sci(None), "1.2345e3"),
+            (1234.5, Notation::Sci(Some(2)), "1.2e3"),
+            (0.00012, Notation::Eng(None), "120e-6"),
+            (-47000.0, Notation::Eng(Some(3)), "-47.0e3"),
+            (1.0, Notation::Eng(None), "1e0"),
+            (f64::NAN, Notation::Fixed(2), "NaN"),
+        ] {
+            let formatted = format_number(&num.into(), Base::Dec, style(notation));
+            assert_eq!(formatted, expected, "{num} {notation}");
+        }
+        let big = Number::Int(BigInt::from(9_996_000));
+        for (notation, expected) in [
+            (Notation::Sig(3), "10000000"),
+            (Notation::Sci(None), "9.996e6"),
+            (Notation::Eng(Some(5)), "9.9960e6"),
+            (Notation::Fixed(1), "9996000.0"),
+        ] {
+            assert_eq!(format_number(&big, Base::Dec, style(notation)), expected);
+        }
+    }
+
+    #[test]
+    fn format_locales() {
+        let style = Style {
+            notation: Notation::Fixed(2),
+            group: true,
+            mark: Mark::Comma,
+        };
+        assert_eq!(
+            format_number(&1234567.891.into(), Base::Dec, style),
+            "1.234.567,89"
+        );
+        assert_eq!(format_number(&(-999.0).into(), Base::Dec, style), "-999,00");
+        let list = Value::List(vec![0.3.into(), Value::List(vec![1.0.into()])]);
+        assert_eq!(format_value(&list, Base::Dec, style), "[0,30; [1,00]]");
+        let style = Style {
+            notation: Notation::Auto,
+            group: true,
+            mark: Mark::Point,
+        };
+        assert_eq!(format_number(&1234.5.into(), Base::Dec, style), "1,234.5");
+        assert_eq!(format_number(&4096.0.into(), Base::Hex, style), "0x1000");
+        let list = Value::List(vec![1e6.into(), 2.5.into()]);
+        assert_eq!(format_value(&list, Base::Dec, style), "[1,000,000, 2.5]");
+    }
+
+    #[test]
+    fn parse_notations() {
+        assert_eq!(Notation::parse("fixed", Some("2")), Ok(Notation::Fixed(2)));
+        assert_eq!(Notation::parse("sci", None), Ok(Notation::Sci(None)));
+        assert!(Notation::parse("fixed", None).is_err());
+        assert!(Notation::parse("sig", Some("40")).is_err());
+        assert!(Notation::parse("roman", None).is_err());
+        assert_eq!("comma".parse(), Ok(Mark::Comma));
+        for base in [Base::Dec, Base::Hex, Base::Bin, Base::Oct] {
+            assert_eq!(base.to_string().parse(), Ok(base));
+        }
+    }
+
+    #[test]
+    fn format_big_integers() {
+        let big = Number::Int(num_bigint::BigInt::from(u64::MAX) * 16);
+        assert_eq!(
+            format_number(&big, Base::Dec, Style::default()),
+            "295147905179352825840"
+        );
+        assert_eq!(
+            format_number(&big, Base::Hex, Style::default()),
+            "0xffffffffffffffff0"
+        );
+        assert_eq!(
+            format_number(&Number::Int((-5).into()), Base::Bin, Style::default()),
+            "-0b101"
+        );
+    }
+}
@@ -0,0 +1,72 @@
+use crate::source::OwnedSource;
+use crate::stack::Value;
+use crate::vm::Chunk;
+use miette::SourceSpan;
+use std::fmt;
+use std::rc::Rc;
+
+/// A compiled `x -> ...` expression, its body runs as a frame on the caller's stack.
+pub struct Lambda {
+    pub params: Vec<String>,
+    /// Variables the body uses besides its parameters, captured when the lambda
+    /// is evaluated and restored after every call.
+    pub names: Vec<String>,
+    pub chunk: Rc<Chunk>,
+    /// The whole `x -> ...` in the source, shown when the function is printed.
+    pub span: SourceSpan,
+}
+
+/// A function value, made by a lambda or by combining other functions.
+pub enum Function {
+    Closure {
+        lambda: Rc<Lambda>,
+        captures: Vec<(String, Value)>,
+        source: Rc<OwnedSource>,
+    },
+    /// `compose(f, g)` applies `g` first, then `f` to its result.
+    Compose(Vec<Rc<Function>>),
+}
+
+impl Function {
+    /// The number of arguments a call needs.
+    pub fn arity(&self) -> usize {
+        match self {
+            Function::Closure { lambda, .. } => lambda.params.len(),
+            Function::Compose(functions) => functions.last().map_or(1, |inner| inner.arity()),
+        }
+    }
+}
+
+impl fmt::Display for Function {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Function::Closure { lambda, source, .. } => {
+                let start = lambda.span.offset();
+                write!(f, "{}", &source.text[start..start + lambda.span.len()])
+            }
+            Function::Compose(functions) => {
+                write!(f, "compose(")?;
+                for (i, function) in functions.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{}", function)?;
+                }
+                write!(f, ")")
+            }
+        }
+    }
+}
+
+impl fmt::Debug for Function {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Function({})", self)
+    }
+}
+
+// Functions are only equal to themselves
+impl PartialEq for Function {
+    fn eq(&self, other: &Self) -> bool {
+        std::ptr::eq(self, other)
+    }
+}
@@ -0,0 +1,197 @@
+use crate::compiler;
+use crate::source::Source;
+use crate::vm::{Globals, Settings, Vm};
+use miette::{Result, miette};
+use std::fs;
+use std::path::Path;
+
+/// Evaluates nex source one input at a time, keeping variables and settings
+/// between calls. This is what the REPL runs on and the entry point for
+/// embedding nex in other programs.
+#[derive(Default)]
+pub struct Interpreter {
+    pub settings: Settings,
+    globals: Globals,
+}
+
+impl Interpreter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_settings(mut self, settings: Settings) -> Self {
+        self.settings = settings;
+        self
+    }
+
+    pub fn eval(&mut self, source: &str) -> Result<String> {
+        let chunk = compiler::compile(source)?;
+        let mut vm = Vm::new(source, chunk)
+            .with_settings(self.settings)
+            .with_globals(std::mem::take(&mut self.globals));
+        let result = vm.eval();
+        self.globals = vm.into_globals();
+        result
+    }
+
+    /// Runs a `.nex` script, whose imports are resolved relative to it.
+    pub fn run_file(&mut self, path: impl AsRef<Path>) -> Result<String> {
+        let path = path.as_ref();
+        let read = fs::canonicalize(path).and_then(|file| Ok((fs::read_to_string(&file)?, file)));
+        let (source, file) =
+            read.map_err(|error| miette!("Could not read {}: {error}", path.display()))?;
+        let name = path.display().to_string();
+        let chunk = compiler::compile_source(Source::new(&name, &source))?;
+        let mut vm = Vm::new(&source, chunk)
+            .with_name(&name)
+            .with_settings(self.settings)
+            .with_globals(std::mem::take(&mut self.globals))
+            .with_file(file);
+        let result = vm.eval();
+        self.globals = vm.into_globals();
+        result
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::format::{Mark, Notation, Style};
+    use crate::natives::AngleMode;
+
+    #[test]
+    fn keeps_variables() {
+        let mut interpreter = Interpreter::new();
+        interpreter.eval("$x = 4").unwrap();
+        assert_eq!(interpreter.eval("$x * 2").unwrap(), "8");
+    }
+
+    #[test]
+    fn angle_mode() {
+        let mut interpreter = Interpreter::new().with_settings(Settings {
+            angle: AngleMode::Deg,
+            ..Default::default()
+        });
+        assert_eq!(interpreter.eval("cos(180)").unwrap(), "-1");
+        assert_eq!(interpreter.eval("atan2(1, 1)").unwrap(), "45");
+        interpreter.settings.angle = AngleMode::Grad;
+        assert_eq!(interpreter.eval("asin(1)").unwrap(), "100");
+        interpreter.settings.angle = AngleMode::Rad;
+        assert_eq!(interpreter.eval("acos(-1)").unwrap(), "3.141592653589793");
+    }
+
+    #[test]
+    fn output_style() {
+        let mut interpreter = Interpreter::new();
+        interpreter.settings.style = Style {
+            notation: Notation::Fixed(2),
+            group: true,
+            mark: Mark::Comma,
+        };
+        assert_eq!(
+            interpreter.eval("12345.678 * 1 km").unwrap(),
+            "12.345,68 km"
+        );
+        assert_eq!(interpreter.eval("[0.1 + 0.2, 1]").unwrap(), "[0,30; 1,00]");
+        assert_eq!(
+            interpreter.eval(r#"format("{} / {:.0}", 1, 2.4)"#).unwrap(),
+            "1,00 / 2"
+        );
+    }
+
+    fn script(dir: &Path, name: &str, source: &str) -> std::path::PathBuf {
+        let path = dir.join(name);
+        fs::write(&path, source).unwrap();
+        path
+    }
+
+    // Line and column of the first label of an error, as miette renders them
+    fn location(err: &dyn miette::Diagnostic) -> (String, usize, usize) {
+        let label = err.labels().unwrap().next().unwrap();
+        let contents = err
+            .source_code()
+            .unwrap()
+            .read_span(label.inner(), 0, 0)
+            .unwrap();
+        let name = contents.name().unwrap().to_string();
+        (name, contents.line() + 1, contents.column() + 1)
+    }
+
+    #[test]
+    fn named_locations() {
+        let dir = std::env::temp_dir().join(format!("nex-locations-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let main = script(&dir, "main.nex", "# total\nlet a = 1\nlet b = 2 / 0\n");
+        let err = Interpreter::new().run_file(&main).unwrap_err();
+        assert_eq!(location(err.as_ref()), (main.display().to_string(), 3, 9));
+
+        script(&dir, "lib.nex", "let x = 1\nlet y = é\n");
+        let main = script(&dir, "uses.nex", "# «lib»\nimport \"lib.nex\"");
+        let err = Interpreter::new().run_file(&main).unwrap_err();
+        assert_eq!(location(err.as_ref()), (main.display().to_string(), 2, 1));
+        let cause = err.related().unwrap().next().unwrap();
+        let lib = dir.join("lib.nex").display().to_string();
+        assert_eq!(location(cause), (lib, 2, 9));
+
+        let err = Interpreter::new().eval("$a = 1\n$a + $x").unwrap_err();
+        assert_eq!(location(err.as_ref()), ("<input>".to_string(), 2, 6));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn functions_outlive_their_input() {
+        let mut interpreter = Interpreter::new();
+        interpreter.eval("$rate = 0.5").unwrap();
+        interpreter.eval("$tax = x -> $x * $rate").unwrap();
+        interpreter.eval("$rate = 2").unwrap();
+        assert_eq!(interpreter.eval("$tax(10)").unwrap(), "5");
+        assert_eq!(interpreter.eval("$tax").unwrap(), "x -> $x * $rate");
+        interpreter.eval("$bad = x -> $x / 0").unwrap();
+        let err = interpreter.eval("1 + $bad(2)").unwrap_err();
+        assert_eq!(location(err.as_ref()), ("<input>".to_string(), 1, 5));
+        // The cause points into the input that defined the function
+        let cause = err.related().unwrap().next().unwrap();
+        assert_eq!(cause.to_string(), "Division by zero!");
+        assert_eq!(location(cause), ("<input>".to_string(), 1, 13));
+    }
+
+    #[test]
+    fn scripts_and_imports() {
+        let dir = std::env::temp_dir().join(format!("nex-scripts-{}", std::process::id()));
+        fs::create_dir_all(dir.join("lib")).unwrap();
+        script(
+            &dir,
+            "lib/rates.nex",
+            "# shared rates\nlet vat = 0.2\nimport \"fees.nex\"\n",
+        );
+        script(&dir, "lib/fees.nex", "let fee = 5 # flat\n");
+        let main = script(
+            &dir,
+            "main.nex",
+            "import \"lib/rates.nex\"\nlet net = 100\n$net * (1 + $vat) + $fee\n",
+        );
+        let mut interpreter = Interpreter::new();
+        assert_eq!(interpreter.run_file(&main).unwrap(), "125");
+        assert_eq!(interpreter.eval("$fee").unwrap(), "5");
+
+        script(&dir, "a.nex", "import \"b.nex\"");
+        script(&dir, "b.nex", "1\nimport \"a.nex\"");
+        let err = interpreter.run_file(dir.join("a.nex")).unwrap_err();
+        assert_eq!(err.to_string(), "Error in imported file b.nex!");
+        let cycle = err.related().unwrap().next().unwrap();
+        assert_eq!(cycle.to_string(), "Import cycle!");
+
+        let broken = script(&dir, "broken.nex", "import \"missing.nex\"");
+        let err = interpreter.run_file(&broken).unwrap_err();
+        assert_eq!(err.to_string(), "Could not import missing.nex!");
+        script(&dir, "bad.nex", "let x = 1 +");
+        let err = interpreter
+            .eval(&format!(
+                "import {:?}",
+                dir.join("bad.nex").display().to_string()
+            ))
+            .unwrap_err();
+        assert!(err.to_string().starts_with("Error in imported file"));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
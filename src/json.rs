@@ -0,0 +1,63 @@
+use miette::{JSONReportHandler, Report, Result};
+use std::fmt::Write;
+
+/// Renders the outcome of an evaluation as a JSON object for other programs:
+/// `{"value": "..."}` on success, or `{"error": {...}}` carrying the message,
+/// help and labelled byte spans of the diagnostic.
+pub fn render(result: &Result<String>) -> String {
+    match result {
+        Ok(value) => format!(r#"{{"value": "{}"}}"#, escape(value)),
+        Err(report) => format!(r#"{{"error": {}}}"#, render_error(report)),
+    }
+}
+
+fn render_error(report: &Report) -> String {
+    let mut out = String::new();
+    JSONReportHandler::new()
+        .render_report(&mut out, report.as_ref())
+        .expect("Failed to write to JSON buffer");
+    out
+}
+
+fn escape(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    for ch in text.chars() {
+        match ch {
+            '"' => out.push_str(r#"\""#),
+            '\\' => out.push_str(r"\\"),
+            '\n' => out.push_str(r"\n"),
+            '\r' => out.push_str(r"\r"),
+            '\t' => out.push_str(r"\t"),
+            ch if ch.is_control() => write!(out, r"\u{:04x}", ch as u32).unwrap(),
+            ch => out.push(ch),
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::Interpreter;
+
+    #[test]
+    fn json_values() {
+        let mut interpreter = Interpreter::new();
+        let result = interpreter.eval(r#""say \"hi\"\n""#);
+        assert_eq!(render(&result), r#"{"value": "say \"hi\"\n"}"#);
+        assert_eq!(render(&interpreter.eval("2 m * 3")), r#"{"value": "6 m"}"#);
+        assert_eq!(escape("\u{1}"), r"\u0001");
+    }
+
+    #[test]
+    fn json_errors() {
+        let result = Interpreter::new().eval("1/0");
+        let json = render(&result);
+        assert!(json.starts_with(r#"{"error": {"message": "Division by zero!","#));
+        assert!(json.contains(r#""help": "#));
+        assert!(json.contains(r#""span": {"offset": 0,"length": 3}"#));
+        assert!(json.ends_with("}}"));
+        let json = render(&Interpreter::new().eval("(1 + 2"));
+        assert!(json.contains(r#""labels": [{"label": "#));
+    }
+}
@@ -1,57 +1,86 @@
+use crate::source::Source;
 use std::iter::Peekable;
 
-#[derive(PartialEq, Debug)]
+#[derive(PartialEq, Debug, Clone)]
 pub enum TokenKind {
     Plus,
     Minus,
     Div,
+    IntDiv,
     Mult,
+    MatMul,
     Mod,
+    BitAnd,
+    BitOr,
+    Xor,
+    BitNot,
+    Shl,
+    Shr,
+    Caret,
+    Bang,
+    To,
+    Of,
     Equal,
     Lparen,
     Rparen,
+    Lbracket,
+    Rbracket,
+    Lbrace,
+    Rbrace,
+    DotDot,
+    DotDotEq,
+    Arrow,
     Num(String),
+    Str(String),
     Ident(String),
     Var,
-    Sin,
-    Cos,
-    Tan,
-    Log,
-    Pow,
+    Let,
+    Import,
+    For,
+    Comma,
+    Semicolon,
+    Newline,
     Illegal,
 }
 
+#[derive(Clone)]
 pub struct Token {
     pub kind: TokenKind,
-    pub offset: u8, //NOTE: REMEMBER TO SUBTRACT 1 when using the offset
+    pub offset: usize, //NOTE: REMEMBER TO SUBTRACT 1 when using the offset
 }
 
+#[derive(Clone)]
 pub struct Lexer<'a> {
     chars: Peekable<std::str::Chars<'a>>,
-    pub source: &'a str,
-    offset: u8,
+    pub source: Source<'a>,
+    offset: usize,
+    // Open brackets, newlines only separate statements outside of them
+    depth: usize,
 }
 
 impl<'a> Lexer<'a> {
-    pub fn new(source: &'a str) -> Self {
+    pub fn new(source: impl Into<Source<'a>>) -> Self {
+        let source = source.into();
         Self {
             source,
-            chars: source.chars().peekable(),
+            chars: source.text.chars().peekable(),
             offset: 0,
+            depth: 0,
         }
     }
 
     fn advance(&mut self) -> Option<char> {
         while let Some(&ch) = self.chars.peek() {
-            if ch.is_whitespace() {
+            if ch.is_whitespace() && (ch != '\n' || self.depth > 0) {
                 self.chars.next();
-                self.offset += 1;
+                self.offset += ch.len_utf8();
             } else {
                 break;
             }
         }
-        self.offset += 1;
-        self.chars.next()
+        let ch = self.chars.next()?;
+        self.offset += ch.len_utf8();
+        Some(ch)
     }
 
     fn make_token(&self, kind: TokenKind) -> Token {
@@ -61,13 +90,38 @@ impl<'a> Lexer<'a> {
         }
     }
 
+    fn eat_while(&mut self, token_str: &mut String, pred: impl Fn(char) -> bool) {
+        while let Some(&ch) = self.chars.peek() {
+            if !pred(ch) {
+                break;
+            }
+            self.chars.next();
+            self.offset += ch.len_utf8();
+            token_str.push(ch);
+        }
+    }
+
+    // `e` only starts an exponent when digits follow, so `2e` stays a number and an ident
+    fn at_exponent(&self) -> bool {
+        let mut ahead = self.chars.clone();
+        if !matches!(ahead.next(), Some('e' | 'E')) {
+            return false;
+        }
+        match ahead.next() {
+            Some('+' | '-') => ahead.next().is_some_and(|ch| ch.is_ascii_digit()),
+            Some(ch) => ch.is_ascii_digit(),
+            None => false,
+        }
+    }
+
     fn match_ident(&self, ident: &str) -> TokenKind {
         match ident {
-            "sin" => TokenKind::Sin,
-            "cos" => TokenKind::Cos,
-            "tan" => TokenKind::Tan,
-            "log" => TokenKind::Log,
-            "pow" => TokenKind::Pow,
+            "xor" => TokenKind::Xor,
+            "to" | "in" => TokenKind::To,
+            "of" => TokenKind::Of,
+            "let" => TokenKind::Let,
+            "import" => TokenKind::Import,
+            "for" => TokenKind::For,
             _ => TokenKind::Ident(ident.to_string()),
         }
     }
@@ -81,14 +135,98 @@ impl Iterator for Lexer<'_> {
         let c = self.advance()?;
         token_str.push(c);
         match c {
-            '(' => Some(self.make_token(TokenKind::Lparen)),
-            ')' => Some(self.make_token(TokenKind::Rparen)),
+            '(' => {
+                self.depth += 1;
+                Some(self.make_token(TokenKind::Lparen))
+            }
+            ')' => {
+                self.depth = self.depth.saturating_sub(1);
+                Some(self.make_token(TokenKind::Rparen))
+            }
+            '[' => {
+                self.depth += 1;
+                Some(self.make_token(TokenKind::Lbracket))
+            }
+            ']' => {
+                self.depth = self.depth.saturating_sub(1);
+                Some(self.make_token(TokenKind::Rbracket))
+            }
+            '{' => Some(self.make_token(TokenKind::Lbrace)),
+            '}' => Some(self.make_token(TokenKind::Rbrace)),
+            '.' if self.chars.peek() == Some(&'.') => {
+                self.advance()?;
+                if self.chars.next_if_eq(&'=').is_some() {
+                    self.offset += 1;
+                    Some(self.make_token(TokenKind::DotDotEq))
+                } else {
+                    Some(self.make_token(TokenKind::DotDot))
+                }
+            }
+            ';' => Some(self.make_token(TokenKind::Semicolon)),
+            '\n' => Some(self.make_token(TokenKind::Newline)),
             '+' => Some(self.make_token(TokenKind::Plus)),
-            '-' => Some(self.make_token(TokenKind::Minus)),
+            '-' => {
+                if self.chars.next_if_eq(&'>').is_some() {
+                    self.offset += 1;
+                    Some(self.make_token(TokenKind::Arrow))
+                } else {
+                    Some(self.make_token(TokenKind::Minus))
+                }
+            }
             '*' => Some(self.make_token(TokenKind::Mult)),
-            '/' => Some(self.make_token(TokenKind::Div)),
+            '@' => Some(self.make_token(TokenKind::MatMul)),
+            '/' => {
+                if self.chars.peek() == Some(&'/') {
+                    self.advance()?;
+                    Some(self.make_token(TokenKind::IntDiv))
+                } else {
+                    Some(self.make_token(TokenKind::Div))
+                }
+            }
             '%' => Some(self.make_token(TokenKind::Mod)),
+            '&' => Some(self.make_token(TokenKind::BitAnd)),
+            '|' => Some(self.make_token(TokenKind::BitOr)),
+            '~' => Some(self.make_token(TokenKind::BitNot)),
+            '^' => Some(self.make_token(TokenKind::Caret)),
+            '!' => Some(self.make_token(TokenKind::Bang)),
+            '<' | '>' => {
+                if self.chars.peek() != Some(&c) {
+                    return Some(self.make_token(TokenKind::Illegal));
+                }
+                self.advance()?;
+                if c == '<' {
+                    Some(self.make_token(TokenKind::Shl))
+                } else {
+                    Some(self.make_token(TokenKind::Shr))
+                }
+            }
+            // Strings keep their quotes and escapes, the parser unescapes them
+            '"' => {
+                while let Some(ch) = self.chars.next() {
+                    self.offset += ch.len_utf8();
+                    token_str.push(ch);
+                    match ch {
+                        '"' => break,
+                        '\\' => {
+                            if let Some(escaped) = self.chars.next() {
+                                self.offset += escaped.len_utf8();
+                                token_str.push(escaped);
+                            }
+                        }
+                        _ => (),
+                    }
+                }
+                Some(self.make_token(TokenKind::Str(token_str)))
+            }
+            // Comments run to the end of the line, the newline still ends the statement
+            '#' => {
+                while let Some(ch) = self.chars.next_if(|&ch| ch != '\n') {
+                    self.offset += ch.len_utf8();
+                }
+                self.next()
+            }
             '$' => Some(self.make_token(TokenKind::Var)),
+            ',' => Some(self.make_token(TokenKind::Comma)),
             '=' => Some(self.make_token(TokenKind::Equal)),
             'a'..='z' | 'A'..='Z' | '_' => {
                 while let Some(&ch) = self.chars.peek() {
@@ -101,21 +239,27 @@ impl Iterator for Lexer<'_> {
                 Some(self.make_token(self.match_ident(&token_str)))
             }
             '0'..='9' => {
-                while let Some(&ch) = self.chars.peek() {
-                    if ch.is_ascii_digit() {
-                        token_str.push(self.advance()?)
-                    } else if ch == '.' {
-                        let dot = self.advance()?;
-                        token_str.push(dot);
-                        while let Some(&c) = self.chars.peek() {
-                            if c.is_ascii_digit() {
-                                token_str.push(self.advance()?);
-                            } else {
-                                break;
-                            }
+                if c == '0' && matches!(self.chars.peek(), Some('x' | 'X' | 'b' | 'B' | 'o' | 'O'))
+                {
+                    token_str.push(self.advance()?);
+                    self.eat_while(&mut token_str, |ch| ch.is_ascii_alphanumeric() || ch == '_');
+                } else {
+                    // `1..5` is a range, the dots do not belong to the number
+                    while let Some(&ch) = self.chars.peek() {
+                        if ch == '.' && self.chars.clone().nth(1) == Some('.') {
+                            break;
                         }
-                    } else {
-                        break;
+                        if !(ch.is_ascii_digit() || ch == '_' || ch == '.') {
+                            break;
+                        }
+                        token_str.push(self.advance()?);
+                    }
+                    if self.at_exponent() {
+                        token_str.push(self.advance()?);
+                        if let Some(&('+' | '-')) = self.chars.peek() {
+                            token_str.push(self.advance()?);
+                        }
+                        self.eat_while(&mut token_str, |ch| ch.is_ascii_digit() || ch == '_');
                     }
                 }
                 Some(self.make_token(TokenKind::Num(token_str)))
@@ -131,10 +275,12 @@ mod tests {
 
     #[test]
     fn lex_parens() {
-        let source = "()";
+        let source = "()[]";
         let mut lexer = Lexer::new(source);
         assert_eq!(lexer.next().unwrap().kind, TokenKind::Lparen);
         assert_eq!(lexer.next().unwrap().kind, TokenKind::Rparen);
+        assert_eq!(lexer.next().unwrap().kind, TokenKind::Lbracket);
+        assert_eq!(lexer.next().unwrap().kind, TokenKind::Rbracket);
     }
 
     #[test]
@@ -148,6 +294,31 @@ mod tests {
         match_number(&mut lexer, "6.99".to_string());
     }
 
+    #[test]
+    fn lex_num_literal_forms() {
+        let source = "1e9 6.02e23 2.5E-3 0xFF 0b1010 0o755 1_000_000 0xZZ";
+        let mut lexer = Lexer::new(source);
+        match_number(&mut lexer, "1e9".to_string());
+        match_number(&mut lexer, "6.02e23".to_string());
+        match_number(&mut lexer, "2.5E-3".to_string());
+        match_number(&mut lexer, "0xFF".to_string());
+        match_number(&mut lexer, "0b1010".to_string());
+        match_number(&mut lexer, "0o755".to_string());
+        match_number(&mut lexer, "1_000_000".to_string());
+        match_number(&mut lexer, "0xZZ".to_string());
+    }
+
+    #[test]
+    fn lex_num_without_exponent() {
+        let source = "2e";
+        let mut lexer = Lexer::new(source);
+        match_number(&mut lexer, "2".to_string());
+        assert_eq!(
+            lexer.next().unwrap().kind,
+            TokenKind::Ident("e".to_string())
+        );
+    }
+
     fn match_number(lexer: &mut Lexer, num: String) {
         let token_kind = lexer.next().unwrap().kind;
         match token_kind {
@@ -158,23 +329,78 @@ mod tests {
 
     #[test]
     fn lex_arithmetic_ops() {
-        let source = "+ - * / %";
+        let source = "+ - * / % // ! @";
         let mut lexer = Lexer::new(source);
         assert_eq!(lexer.next().unwrap().kind, TokenKind::Plus);
         assert_eq!(lexer.next().unwrap().kind, TokenKind::Minus);
         assert_eq!(lexer.next().unwrap().kind, TokenKind::Mult);
         assert_eq!(lexer.next().unwrap().kind, TokenKind::Div);
         assert_eq!(lexer.next().unwrap().kind, TokenKind::Mod);
+        assert_eq!(lexer.next().unwrap().kind, TokenKind::IntDiv);
+        assert_eq!(lexer.next().unwrap().kind, TokenKind::Bang);
+        assert_eq!(lexer.next().unwrap().kind, TokenKind::MatMul);
+    }
+
+    #[test]
+    fn lex_bitwise_ops() {
+        let source = "& | xor ~ << >> <";
+        let mut lexer = Lexer::new(source);
+        assert_eq!(lexer.next().unwrap().kind, TokenKind::BitAnd);
+        assert_eq!(lexer.next().unwrap().kind, TokenKind::BitOr);
+        assert_eq!(lexer.next().unwrap().kind, TokenKind::Xor);
+        assert_eq!(lexer.next().unwrap().kind, TokenKind::BitNot);
+        assert_eq!(lexer.next().unwrap().kind, TokenKind::Shl);
+        assert_eq!(lexer.next().unwrap().kind, TokenKind::Shr);
+        assert_eq!(lexer.next().unwrap().kind, TokenKind::Illegal);
+    }
+
+    #[test]
+    fn lex_units() {
+        let source = "60 mph to m/s^2 in";
+        let mut lexer = Lexer::new(source);
+        match_number(&mut lexer, "60".to_string());
+        assert_eq!(
+            lexer.next().unwrap().kind,
+            TokenKind::Ident("mph".to_string())
+        );
+        assert_eq!(lexer.next().unwrap().kind, TokenKind::To);
+        assert_eq!(
+            lexer.next().unwrap().kind,
+            TokenKind::Ident("m".to_string())
+        );
+        assert_eq!(lexer.next().unwrap().kind, TokenKind::Div);
+        assert_eq!(
+            lexer.next().unwrap().kind,
+            TokenKind::Ident("s".to_string())
+        );
+        assert_eq!(lexer.next().unwrap().kind, TokenKind::Caret);
+        match_number(&mut lexer, "2".to_string());
+        assert_eq!(lexer.next().unwrap().kind, TokenKind::To);
     }
 
     #[test]
     fn lex_idents_and_var() {
-        let source = "pow sin cos tan $hello = ";
+        let source = "pow(sin, cos) tan $hello = ";
         let mut lexer = Lexer::new(source);
-        assert_eq!(lexer.next().unwrap().kind, TokenKind::Pow);
-        assert_eq!(lexer.next().unwrap().kind, TokenKind::Sin);
-        assert_eq!(lexer.next().unwrap().kind, TokenKind::Cos);
-        assert_eq!(lexer.next().unwrap().kind, TokenKind::Tan);
+        assert_eq!(
+            lexer.next().unwrap().kind,
+            TokenKind::Ident("pow".to_string())
+        );
+        assert_eq!(lexer.next().unwrap().kind, TokenKind::Lparen);
+        assert_eq!(
+            lexer.next().unwrap().kind,
+            TokenKind::Ident("sin".to_string())
+        );
+        assert_eq!(lexer.next().unwrap().kind, TokenKind::Comma);
+        assert_eq!(
+            lexer.next().unwrap().kind,
+            TokenKind::Ident("cos".to_string())
+        );
+        assert_eq!(lexer.next().unwrap().kind, TokenKind::Rparen);
+        assert_eq!(
+            lexer.next().unwrap().kind,
+            TokenKind::Ident("tan".to_string())
+        );
         assert_eq!(lexer.next().unwrap().kind, TokenKind::Var);
         assert_eq!(
             lexer.next().unwrap().kind,
@@ -183,6 +409,105 @@ mod tests {
         assert_eq!(lexer.next().unwrap().kind, TokenKind::Equal);
     }
 
+    #[test]
+    fn lex_separators() {
+        let kinds: Vec<_> = Lexer::new("1; 2\n[3,\n 4]\n")
+            .map(|token| token.kind)
+            .collect();
+        assert_eq!(
+            kinds,
+            vec![
+                TokenKind::Num("1".to_string()),
+                TokenKind::Semicolon,
+                TokenKind::Num("2".to_string()),
+                TokenKind::Newline,
+                TokenKind::Lbracket,
+                TokenKind::Num("3".to_string()),
+                TokenKind::Comma,
+                TokenKind::Num("4".to_string()),
+                TokenKind::Rbracket,
+                TokenKind::Newline,
+            ]
+        );
+    }
+
+    #[test]
+    fn lex_ranges() {
+        let tokens: Vec<_> = Lexer::new("for i in 1..=12 { 1.5..$n }")
+            .map(|token| (token.kind, token.offset))
+            .collect();
+        assert_eq!(
+            tokens,
+            vec![
+                (TokenKind::For, 3),
+                (TokenKind::Ident("i".to_string()), 5),
+                (TokenKind::To, 8),
+                (TokenKind::Num("1".to_string()), 10),
+                (TokenKind::DotDotEq, 13),
+                (TokenKind::Num("12".to_string()), 15),
+                (TokenKind::Lbrace, 17),
+                (TokenKind::Num("1.5".to_string()), 21),
+                (TokenKind::DotDot, 23),
+                (TokenKind::Var, 24),
+                (TokenKind::Ident("n".to_string()), 25),
+                (TokenKind::Rbrace, 27),
+            ]
+        );
+        let kinds: Vec<_> = Lexer::new("x -> -$x").map(|token| token.kind).collect();
+        assert_eq!(
+            kinds,
+            vec![
+                TokenKind::Ident("x".to_string()),
+                TokenKind::Arrow,
+                TokenKind::Minus,
+                TokenKind::Var,
+                TokenKind::Ident("x".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn lex_comments() {
+        let tokens: Vec<_> =
+            Lexer::new("let r = 2 # radius, in m\n# only a comment\nimport \"a.nex\" #")
+                .map(|token| (token.kind, token.offset))
+                .collect();
+        assert_eq!(
+            tokens,
+            vec![
+                (TokenKind::Let, 3),
+                (TokenKind::Ident("r".to_string()), 5),
+                (TokenKind::Equal, 7),
+                (TokenKind::Num("2".to_string()), 9),
+                (TokenKind::Newline, 25),
+                (TokenKind::Newline, 42),
+                (TokenKind::Import, 48),
+                (TokenKind::Str("\"a.nex\"".to_string()), 56),
+            ]
+        );
+    }
+
+    #[test]
+    fn lex_byte_offsets() {
+        let offsets: Vec<_> = Lexer::new("é +\u{a0}1").map(|token| token.offset).collect();
+        assert_eq!(offsets, vec![2, 4, 7]);
+    }
+
+    #[test]
+    fn lex_strings() {
+        let source = r#""a b" + "say \"hi\"" "€ 5"#;
+        let mut lexer = Lexer::new(source);
+        let token = lexer.next().unwrap();
+        assert_eq!(token.kind, TokenKind::Str(r#""a b""#.to_string()));
+        assert_eq!(token.offset, 5);
+        assert_eq!(lexer.next().unwrap().kind, TokenKind::Plus);
+        let token = lexer.next().unwrap();
+        assert_eq!(token.kind, TokenKind::Str(r#""say \"hi\"""#.to_string()));
+        let token = lexer.next().unwrap();
+        assert_eq!(token.kind, TokenKind::Str(r#""€ 5"#.to_string()));
+        assert_eq!(token.offset, source.len());
+    }
+
     #[test]
     fn test_offset() {
         let source = "+ - * / %";
@@ -196,8 +521,8 @@ mod tests {
 
     fn verify_offset(lexer: &mut Lexer, expected: char) {
         let tok = lexer.next().unwrap();
-        let mut char_indices = lexer.source.char_indices();
-        let (_, ch) = char_indices.nth((tok.offset - 1) as usize).unwrap();
+        let mut char_indices = lexer.source.text.char_indices();
+        let (_, ch) = char_indices.nth(tok.offset - 1).unwrap();
         if ch != expected {
             panic!("Expected: {expected} got {ch} at offset {}", tok.offset);
         }
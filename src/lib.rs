@@ -0,0 +1,19 @@
+mod compiler;
+pub mod constants;
+pub mod format;
+mod function;
+pub mod interpreter;
+pub mod json;
+mod lexer;
+mod matrix;
+pub mod natives;
+pub mod number;
+mod numeric;
+mod parser;
+mod source;
+mod stack;
+mod units;
+pub mod vm;
+
+pub use interpreter::Interpreter;
+pub use vm::{Echo, Settings, Vm};
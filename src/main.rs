@@ -1,25 +1,24 @@
-use miette::Result;
+use miette::{Result, miette};
+use nex::format::Notation;
+use nex::{Interpreter, Settings, constants, json};
 use std::fs;
 use std::io::{Write, stdin, stdout};
-use vm::Vm;
-mod compiler;
-mod lexer;
-mod parser;
-mod stack;
-mod vm;
+use std::path::PathBuf;
 
 struct Repl {
     history: Vec<String>,
     success: bool,
     input: String,
+    interpreter: Interpreter,
 }
 
 impl Repl {
-    fn new() -> Self {
+    fn new(settings: Settings) -> Self {
         Self {
             history: Vec::new(),
             success: true,
             input: String::new(),
+            interpreter: Interpreter::new().with_settings(settings),
         }
     }
     fn run(&mut self) -> Result<()> {
@@ -29,10 +28,11 @@ impl Repl {
         self.success = true;
         loop {
             self.input.clear();
+            let angle = self.interpreter.settings.angle;
             if self.success {
-                print!("{}>>{} ", GREEN, RESET);
+                print!("{}{}>>{} ", GREEN, angle, RESET);
             } else {
-                print!("{}>>{} ", RED, RESET);
+                print!("{}{}>>{} ", RED, angle, RESET);
             }
             stdout().flush().expect("Failed to flush std out");
             stdin()
@@ -69,27 +69,149 @@ impl Repl {
                         println!("-----------------------------");
                     }
                 }
+            } else if let Some(&":base") = input.first() {
+                match input.get(1).map(|base| base.parse()) {
+                    Some(Ok(base)) => {
+                        self.interpreter.settings.base = base;
+                        self.success = true;
+                    }
+                    Some(Err(error)) => {
+                        eprintln!("{error}");
+                        self.success = false;
+                    }
+                    None => {
+                        println!("{}", self.interpreter.settings.base);
+                        self.success = true;
+                    }
+                }
+            } else if let Some(&":angle") = input.first() {
+                match input.get(1).map(|angle| angle.parse()) {
+                    Some(Ok(angle)) => {
+                        self.interpreter.settings.angle = angle;
+                        self.success = true;
+                    }
+                    Some(Err(error)) => {
+                        eprintln!("{error}");
+                        self.success = false;
+                    }
+                    None => {
+                        println!("{}", self.interpreter.settings.angle);
+                        self.success = true;
+                    }
+                }
+            } else if let Some(&":mod") = input.first() {
+                match input.get(1).map(|modulo| modulo.parse()) {
+                    Some(Ok(modulo)) => {
+                        self.interpreter.settings.modulo = modulo;
+                        self.success = true;
+                    }
+                    Some(Err(error)) => {
+                        eprintln!("{error}");
+                        self.success = false;
+                    }
+                    None => {
+                        println!("{}", self.interpreter.settings.modulo);
+                        self.success = true;
+                    }
+                }
+            } else if let Some(&":ieee") = input.first() {
+                match input.get(1).copied() {
+                    Some("on") => {
+                        self.interpreter.settings.ieee = true;
+                        self.success = true;
+                    }
+                    Some("off") => {
+                        self.interpreter.settings.ieee = false;
+                        self.success = true;
+                    }
+                    Some(_) => {
+                        eprintln!("Expected :ieee on|off");
+                        self.success = false;
+                    }
+                    None => {
+                        let ieee = self.interpreter.settings.ieee;
+                        println!("{}", if ieee { "on" } else { "off" });
+                        self.success = true;
+                    }
+                }
+            } else if let Some(&":complex") = input.first() {
+                match input.get(1).copied() {
+                    Some("on") => {
+                        self.interpreter.settings.complex = true;
+                        self.success = true;
+                    }
+                    Some("off") => {
+                        self.interpreter.settings.complex = false;
+                        self.success = true;
+                    }
+                    Some(_) => {
+                        eprintln!("Expected :complex on|off");
+                        self.success = false;
+                    }
+                    None => {
+                        let complex = self.interpreter.settings.complex;
+                        println!("{}", if complex { "on" } else { "off" });
+                        self.success = true;
+                    }
+                }
+            } else if let Some(&":echo") = input.first() {
+                match input.get(1).map(|echo| echo.parse()) {
+                    Some(Ok(echo)) => {
+                        self.interpreter.settings.echo = echo;
+                        self.success = true;
+                    }
+                    Some(Err(error)) => {
+                        eprintln!("{error}");
+                        self.success = false;
+                    }
+                    None => {
+                        println!("{}", self.interpreter.settings.echo);
+                        self.success = true;
+                    }
+                }
+            } else if let Some(&":format") = input.first() {
+                let style = &mut self.interpreter.settings.style;
+                let changed = match input.get(1..).unwrap_or_default() {
+                    [] => {
+                        println!("{style}");
+                        Ok(())
+                    }
+                    ["group", "on"] => {
+                        style.group = true;
+                        Ok(())
+                    }
+                    ["group", "off"] => {
+                        style.group = false;
+                        Ok(())
+                    }
+                    ["group", ..] => Err("Expected :format group on|off".to_string()),
+                    ["mark", mark] => mark.parse().map(|mark| style.mark = mark),
+                    [name] => Notation::parse(name, None).map(|notation| style.notation = notation),
+                    [name, digits] => Notation::parse(name, Some(digits))
+                        .map(|notation| style.notation = notation),
+                    _ => Err("Expected :format auto|fixed N|sig N|sci [N]|eng [N]".to_string()),
+                };
+                self.success = changed.is_ok();
+                if let Err(error) = changed {
+                    eprintln!("{error}");
+                }
+            } else if let Some(&":consts") = input.first() {
+                for (name, value, description) in constants::CONSTANTS {
+                    println!("{name:<4} = {value:<20} {description}");
+                }
+                self.success = true;
             } else if let Some(&"quit") = input.first() {
                 return Ok(());
             } else {
-                match compiler::compile(&self.input) {
-                    Ok(chunk) => {
-                        let mut vm = Vm::new(&self.input, chunk);
-                        match vm.eval() {
-                            Ok(result) => {
-                                println!("{result}");
-                                self.success = true;
-                                self.history.push(format!(
-                                    "{} = {}",
-                                    self.input.split('\n').nth(0).unwrap(),
-                                    result
-                                ));
-                            }
-                            Err(error) => {
-                                eprintln!("{:?}", error);
-                                self.success = false;
-                            }
-                        }
+                match self.interpreter.eval(&self.input) {
+                    Ok(result) => {
+                        println!("{result}");
+                        self.success = true;
+                        self.history.push(format!(
+                            "{} = {}",
+                            self.input.split('\n').nth(0).unwrap(),
+                            result
+                        ));
                     }
                     Err(error) => {
                         eprintln!("{:?}", error);
@@ -101,8 +223,84 @@ impl Repl {
     }
 }
 
+// Command line options, `-e` evaluates one expression and a file argument runs a
+// script instead of starting the REPL
+#[derive(Default)]
+struct Options {
+    settings: Settings,
+    expression: Option<String>,
+    script: Option<PathBuf>,
+    json: bool,
+}
+
+fn parse_args(mut args: impl Iterator<Item = String>) -> Result<Options> {
+    let mut options = Options::default();
+    let settings = &mut options.settings;
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-e" => {
+                let expression = args.next().ok_or(miette!("-e expects an expression"))?;
+                options.expression = Some(expression);
+            }
+            "--json" => options.json = true,
+            "--angle" => {
+                let angle = args
+                    .next()
+                    .ok_or(miette!("--angle expects one of deg|rad|grad"))?;
+                settings.angle = angle.parse().map_err(|error: String| miette!(error))?;
+            }
+            "--format" => {
+                let format = args.next().ok_or(miette!(
+                    "--format expects one of auto|fixed:N|sig:N|sci[:N]|eng[:N]"
+                ))?;
+                let (name, digits) = match format.split_once(':') {
+                    Some((name, digits)) => (name, Some(digits)),
+                    None => (format.as_str(), None),
+                };
+                settings.style.notation =
+                    Notation::parse(name, digits).map_err(|error| miette!(error))?;
+            }
+            "--group" => settings.style.group = true,
+            "--mark" => {
+                let mark = args
+                    .next()
+                    .ok_or(miette!("--mark expects one of point|comma"))?;
+                settings.style.mark = mark.parse().map_err(|error: String| miette!(error))?;
+            }
+            "--echo" => {
+                let echo = args
+                    .next()
+                    .ok_or(miette!("--echo expects one of last|all"))?;
+                settings.echo = echo.parse().map_err(|error: String| miette!(error))?;
+            }
+            "--ieee" => settings.ieee = true,
+            "--complex" => settings.complex = true,
+            path if !path.starts_with('-') && options.script.is_none() => {
+                options.script = Some(PathBuf::from(path));
+            }
+            _ => return Err(miette!("Unknown argument {arg}")),
+        }
+    }
+    if options.json && options.expression.is_none() && options.script.is_none() {
+        return Err(miette!(
+            "--json needs an expression with -e or a script to run"
+        ));
+    }
+    Ok(options)
+}
+
 fn main() -> Result<()> {
-    let mut repl = Repl::new();
-    repl.run()?;
+    let options = parse_args(std::env::args().skip(1))?;
+    let mut interpreter = Interpreter::new().with_settings(options.settings);
+    let result = match (options.expression, options.script) {
+        (Some(expression), _) => interpreter.eval(&expression),
+        (None, Some(script)) => interpreter.run_file(script),
+        (None, None) => return Repl::new(options.settings).run(),
+    };
+    if options.json {
+        println!("{}", json::render(&result));
+        std::process::exit(if result.is_ok() { 0 } else { 1 });
+    }
+    println!("{}", result?);
     Ok(())
 }
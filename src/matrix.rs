@@ -0,0 +1,151 @@
+/// A dense matrix stored as a list of rows, all of the same length.
+pub type Matrix = Vec<Vec<f64>>;
+
+// Pivots smaller than this, relative to the largest entry and the size of the
+// matrix, are treated as zero, the matrix is then singular
+const EPSILON: f64 = 1e-12;
+
+pub fn shape(matrix: &Matrix) -> (usize, usize) {
+    (matrix.len(), matrix.first().map_or(0, Vec::len))
+}
+
+pub fn transpose(matrix: &Matrix) -> Matrix {
+    let (rows, cols) = shape(matrix);
+    (0..cols)
+        .map(|col| (0..rows).map(|row| matrix[row][col]).collect())
+        .collect()
+}
+
+/// The caller checks that the columns of `a` match the rows of `b`.
+pub fn multiply(a: &Matrix, b: &Matrix) -> Matrix {
+    let columns = transpose(b);
+    a.iter()
+        .map(|row| {
+            columns
+                .iter()
+                .map(|col| row.iter().zip(col).map(|(x, y)| x * y).sum())
+                .collect()
+        })
+        .collect()
+}
+
+// Gauss-Jordan elimination with partial pivoting of `matrix` next to `rhs`,
+// leaving the solution of `matrix * x = rhs` in `rhs`. Returns the determinant of
+// `matrix`, zero when it is singular.
+fn eliminate(mut matrix: Matrix, rhs: &mut Matrix) -> f64 {
+    let n = matrix.len();
+    let largest = matrix
+        .iter()
+        .flatten()
+        .fold(0.0, |max: f64, x| max.max(x.abs()));
+    let tolerance = EPSILON * largest * n as f64;
+    let mut det = 1.0;
+    for col in 0..n {
+        let pivot = (col..n)
+            .max_by(|&a, &b| matrix[a][col].abs().total_cmp(&matrix[b][col].abs()))
+            .unwrap();
+        if matrix[pivot][col].abs() <= tolerance {
+            return 0.0;
+        }
+        if pivot != col {
+            matrix.swap(pivot, col);
+            rhs.swap(pivot, col);
+            det = -det;
+        }
+        let scale = matrix[col][col];
+        det *= scale;
+        matrix[col].iter_mut().for_each(|x| *x /= scale);
+        rhs[col].iter_mut().for_each(|x| *x /= scale);
+        let (pivot_row, pivot_rhs) = (matrix[col].clone(), rhs[col].clone());
+        for row in (0..n).filter(|&row| row != col) {
+            let factor = matrix[row][col];
+            for (x, p) in matrix[row].iter_mut().zip(&pivot_row) {
+                *x -= factor * p;
+            }
+            for (x, p) in rhs[row].iter_mut().zip(&pivot_rhs) {
+                *x -= factor * p;
+            }
+        }
+    }
+    det
+}
+
+/// Determinant of a square matrix.
+pub fn determinant(matrix: &Matrix) -> f64 {
+    let mut rhs = vec![Vec::new(); matrix.len()];
+    eliminate(matrix.clone(), &mut rhs)
+}
+
+/// Inverse of a square matrix, `None` when it is singular.
+pub fn inverse(matrix: &Matrix) -> Option<Matrix> {
+    let n = matrix.len();
+    let mut identity: Matrix = (0..n)
+        .map(|i| (0..n).map(|j| if i == j { 1.0 } else { 0.0 }).collect())
+        .collect();
+    (eliminate(matrix.clone(), &mut identity) != 0.0).then_some(identity)
+}
+
+/// Solves `a * x = b` for a square `a`, `None` when it is singular.
+pub fn solve(a: &Matrix, b: &Matrix) -> Option<Matrix> {
+    let mut x = b.clone();
+    (eliminate(a.clone(), &mut x) != 0.0).then_some(x)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn approx(a: &Matrix, b: &Matrix) -> bool {
+        a.iter()
+            .flatten()
+            .zip(b.iter().flatten())
+            .all(|(x, y)| (x - y).abs() < 1e-12)
+    }
+
+    #[test]
+    fn products() {
+        let a = vec![vec![1., 2., 3.], vec![4., 5., 6.]];
+        assert_eq!(shape(&a), (2, 3));
+        assert_eq!(
+            transpose(&a),
+            vec![vec![1., 4.], vec![2., 5.], vec![3., 6.]]
+        );
+        assert_eq!(
+            multiply(&a, &transpose(&a)),
+            vec![vec![14., 32.], vec![32., 77.]]
+        );
+    }
+
+    #[test]
+    fn elimination() {
+        let a = vec![vec![2., 1.], vec![1., 3.]];
+        assert_eq!(determinant(&a), 5.);
+        assert_eq!(determinant(&vec![vec![1., 2.], vec![2., 4.]]), 0.);
+        assert_eq!(determinant(&vec![vec![0., 1.], vec![1., 0.]]), -1.);
+        let inv = inverse(&a).unwrap();
+        assert!(approx(&inv, &vec![vec![0.6, -0.2], vec![-0.2, 0.4]]));
+        assert!(approx(
+            &multiply(&a, &inv),
+            &vec![vec![1., 0.], vec![0., 1.]]
+        ));
+        let x = solve(&a, &vec![vec![3.], vec![5.]]).unwrap();
+        assert!(approx(&x, &vec![vec![0.8], vec![1.4]]));
+        assert!(inverse(&vec![vec![1., 2.], vec![2., 4.]]).is_none());
+        assert!(inverse(&vec![vec![0., 0.], vec![0., 0.]]).is_none());
+    }
+
+    #[test]
+    fn small_magnitudes() {
+        let a = vec![vec![1e-13, 0.], vec![0., 1e-13]];
+        assert!(determinant(&a) != 0.);
+        let inv = inverse(&a).unwrap();
+        assert_eq!(inv, vec![vec![1e13, 0.], vec![0., 1e13]]);
+        let b = vec![vec![2e-20, 1e-20], vec![1e-20, 3e-20]];
+        let x = solve(&b, &vec![vec![3e-20], vec![5e-20]]).unwrap();
+        assert!(approx(&x, &vec![vec![0.8], vec![1.4]]));
+        assert_eq!(
+            determinant(&vec![vec![1e-13, 2e-13], vec![2e-13, 4e-13]]),
+            0.
+        );
+    }
+}
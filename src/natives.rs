@@ -0,0 +1,696 @@
+use crate::number::{self, Number};
+use num_complex::Complex64;
+use std::fmt;
+use std::str::FromStr;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum AngleMode {
+    #[default]
+    Rad,
+    Deg,
+    Grad,
+}
+
+impl AngleMode {
+    pub fn to_radians(self, angle: f64) -> f64 {
+        match self {
+            AngleMode::Rad => angle,
+            AngleMode::Deg => angle.to_radians(),
+            AngleMode::Grad => angle * std::f64::consts::PI / 200.,
+        }
+    }
+
+    pub fn from_radians(self, angle: f64) -> f64 {
+        match self {
+            AngleMode::Rad => angle,
+            AngleMode::Deg => angle.to_degrees(),
+            AngleMode::Grad => angle * 200. / std::f64::consts::PI,
+        }
+    }
+}
+
+impl FromStr for AngleMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "rad" => Ok(AngleMode::Rad),
+            "deg" => Ok(AngleMode::Deg),
+            "grad" => Ok(AngleMode::Grad),
+            _ => Err(format!(
+                "Unknown angle mode {s}, expected one of deg|rad|grad"
+            )),
+        }
+    }
+}
+
+impl fmt::Display for AngleMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AngleMode::Rad => write!(f, "rad"),
+            AngleMode::Deg => write!(f, "deg"),
+            AngleMode::Grad => write!(f, "grad"),
+        }
+    }
+}
+
+/// Sign convention of `%` for negative operands: truncated remainder takes the sign
+/// of the dividend, floored modulo the sign of the divisor and Euclidean modulo is
+/// never negative.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum ModMode {
+    #[default]
+    Trunc,
+    Floor,
+    Euclid,
+}
+
+impl ModMode {
+    pub fn apply(self, a: f64, b: f64) -> f64 {
+        match self {
+            ModMode::Trunc => a % b,
+            ModMode::Floor => a - b * (a / b).floor(),
+            ModMode::Euclid => a.rem_euclid(b),
+        }
+    }
+}
+
+impl FromStr for ModMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "trunc" => Ok(ModMode::Trunc),
+            "floor" => Ok(ModMode::Floor),
+            "euclid" => Ok(ModMode::Euclid),
+            _ => Err(format!(
+                "Unknown modulo mode {s}, expected one of trunc|floor|euclid"
+            )),
+        }
+    }
+}
+
+impl fmt::Display for ModMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ModMode::Trunc => write!(f, "trunc"),
+            ModMode::Floor => write!(f, "floor"),
+            ModMode::Euclid => write!(f, "euclid"),
+        }
+    }
+}
+
+/// How a native function relates to the angle mode: trigonometric functions take
+/// an angle as their first argument, their inverses return one.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Angle {
+    None,
+    Takes,
+    Returns,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Arity {
+    Exact(usize),
+    Between(usize, usize),
+    AtLeast(usize),
+    /// Functions of a set of values such as `mean` take this many leading
+    /// arguments and then at least one value, lists among the values are flattened
+    Values(usize),
+}
+
+impl Arity {
+    pub fn accepts(&self, count: usize) -> bool {
+        match *self {
+            Arity::Exact(n) => count == n,
+            Arity::Between(min, max) => (min..=max).contains(&count),
+            Arity::AtLeast(min) => count >= min,
+            Arity::Values(leading) => count > leading,
+        }
+    }
+}
+
+impl fmt::Display for Arity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let plural = |n: usize| if n == 1 { "argument" } else { "arguments" };
+        match *self {
+            Arity::Exact(n) => write!(f, "{} {}", n, plural(n)),
+            Arity::Between(min, max) => write!(f, "{} to {} arguments", min, max),
+            Arity::AtLeast(min) => write!(f, "at least {} {}", min, plural(min)),
+            Arity::Values(leading) => write!(f, "at least {} {}", leading + 1, plural(leading + 1)),
+        }
+    }
+}
+
+/// Functions that need whole values rather than numbers, run by the VM itself.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Builtin {
+    Len,
+    Transpose,
+    Det,
+    Inverse,
+    LinSolve,
+    Concat,
+    Format,
+    Map,
+    Filter,
+    Reduce,
+    SortBy,
+    Compose,
+    Solve,
+    Integrate,
+    Diff,
+}
+
+impl Builtin {
+    /// Numerical methods take an expression followed by the variable it is in,
+    /// as in `solve($x * $x - 2, x, 1)`.
+    pub fn binds_variable(self) -> bool {
+        matches!(self, Builtin::Solve | Builtin::Integrate | Builtin::Diff)
+    }
+}
+
+/// What a native function computes with. Math functions take plain numbers: a
+/// variadic one receives every element of its list arguments, the others apply
+/// element-wise across lists, so `sum([1, 2, 3])` is 6 and `sqrt([1, 4])` is
+/// `[1, 2]`.
+#[derive(Clone, Copy)]
+pub enum Func {
+    Math(fn(&[f64]) -> Number),
+    Builtin(Builtin),
+}
+
+pub struct Native {
+    pub name: &'static str,
+    pub arity: Arity,
+    pub angle: Angle,
+    pub func: Func,
+}
+
+macro_rules! unary {
+    ($name: literal, $method: ident) => {
+        unary!($name, $method, Angle::None)
+    };
+    ($name: literal, $method: ident, $angle: expr) => {
+        Native {
+            name: $name,
+            arity: Arity::Exact(1),
+            angle: $angle,
+            func: Func::Math(|args| args[0].$method().into()),
+        }
+    };
+}
+
+macro_rules! binary {
+    ($name: literal, $method: ident) => {
+        binary!($name, $method, Angle::None)
+    };
+    ($name: literal, $method: ident, $angle: expr) => {
+        Native {
+            name: $name,
+            arity: Arity::Exact(2),
+            angle: $angle,
+            func: Func::Math(|args| args[0].$method(args[1]).into()),
+        }
+    };
+}
+
+pub const NATIVES: &[Native] = &[
+    unary!("sin", sin, Angle::Takes),
+    unary!("cos", cos, Angle::Takes),
+    unary!("tan", tan, Angle::Takes),
+    unary!("asin", asin, Angle::Returns),
+    unary!("acos", acos, Angle::Returns),
+    unary!("atan", atan, Angle::Returns),
+    binary!("atan2", atan2, Angle::Returns),
+    unary!("sinh", sinh),
+    unary!("cosh", cosh),
+    unary!("tanh", tanh),
+    unary!("asinh", asinh),
+    unary!("acosh", acosh),
+    unary!("atanh", atanh),
+    unary!("sqrt", sqrt),
+    unary!("cbrt", cbrt),
+    unary!("abs", abs),
+    unary!("floor", floor),
+    unary!("ceil", ceil),
+    unary!("trunc", trunc),
+    unary!("exp", exp),
+    unary!("ln", ln),
+    binary!("pow", powf),
+    binary!("hypot", hypot),
+    Native {
+        name: "rem",
+        arity: Arity::Exact(2),
+        angle: Angle::None,
+        func: Func::Math(|args| ModMode::Trunc.apply(args[0], args[1]).into()),
+    },
+    Native {
+        name: "mod",
+        arity: Arity::Exact(2),
+        angle: Angle::None,
+        func: Func::Math(|args| ModMode::Floor.apply(args[0], args[1]).into()),
+    },
+    Native {
+        name: "round",
+        arity: Arity::Between(1, 2),
+        angle: Angle::None,
+        func: Func::Math(round),
+    },
+    Native {
+        name: "log",
+        arity: Arity::Between(1, 2),
+        angle: Angle::None,
+        func: Func::Math(|args| {
+            match args.get(1) {
+                Some(base) => args[0].log(*base),
+                None => args[0].log10(),
+            }
+            .into()
+        }),
+    },
+    Native {
+        name: "min",
+        arity: Arity::Values(0),
+        angle: Angle::None,
+        func: Func::Math(|args| args.iter().copied().fold(f64::INFINITY, f64::min).into()),
+    },
+    Native {
+        name: "max",
+        arity: Arity::Values(0),
+        angle: Angle::None,
+        func: Func::Math(|args| {
+            args.iter()
+                .copied()
+                .fold(f64::NEG_INFINITY, f64::max)
+                .into()
+        }),
+    },
+    Native {
+        name: "sum",
+        arity: Arity::Values(0),
+        angle: Angle::None,
+        func: Func::Math(|args| args.iter().sum::<f64>().into()),
+    },
+    Native {
+        name: "mean",
+        arity: Arity::Values(0),
+        angle: Angle::None,
+        func: Func::Math(|args| mean(args).into()),
+    },
+    Native {
+        name: "median",
+        arity: Arity::Values(0),
+        angle: Angle::None,
+        func: Func::Math(|args| quantile(args, 0.5).into()),
+    },
+    Native {
+        name: "mode",
+        arity: Arity::Values(0),
+        angle: Angle::None,
+        func: Func::Math(mode),
+    },
+    Native {
+        name: "variance",
+        arity: Arity::Values(0),
+        angle: Angle::None,
+        func: Func::Math(|args| variance(args).into()),
+    },
+    Native {
+        name: "stdev",
+        arity: Arity::Values(0),
+        angle: Angle::None,
+        func: Func::Math(|args| variance(args).sqrt().into()),
+    },
+    Native {
+        name: "percentile",
+        arity: Arity::Values(1),
+        angle: Angle::None,
+        func: Func::Math(|args| {
+            match args[0] {
+                p if (0.0..=100.0).contains(&p) => quantile(&args[1..], p / 100.),
+                _ => f64::NAN,
+            }
+            .into()
+        }),
+    },
+    Native {
+        name: "gamma",
+        arity: Arity::Exact(1),
+        angle: Angle::None,
+        func: Func::Math(|args| number::gamma(args[0]).into()),
+    },
+    Native {
+        name: "nCr",
+        arity: Arity::Exact(2),
+        angle: Angle::None,
+        func: Func::Math(choose),
+    },
+    Native {
+        name: "choose",
+        arity: Arity::Exact(2),
+        angle: Angle::None,
+        func: Func::Math(choose),
+    },
+    Native {
+        name: "nPr",
+        arity: Arity::Exact(2),
+        angle: Angle::None,
+        func: Func::Math(permute),
+    },
+    Native {
+        name: "re",
+        arity: Arity::Exact(1),
+        angle: Angle::None,
+        func: Func::Math(|args| args[0].into()),
+    },
+    Native {
+        name: "im",
+        arity: Arity::Exact(1),
+        angle: Angle::None,
+        func: Func::Math(|_| 0.0.into()),
+    },
+    Native {
+        name: "arg",
+        arity: Arity::Exact(1),
+        angle: Angle::Returns,
+        func: Func::Math(|args| Complex64::from(args[0]).arg().into()),
+    },
+    Native {
+        name: "conj",
+        arity: Arity::Exact(1),
+        angle: Angle::None,
+        func: Func::Math(|args| args[0].into()),
+    },
+    Native {
+        name: "len",
+        arity: Arity::Exact(1),
+        angle: Angle::None,
+        func: Func::Builtin(Builtin::Len),
+    },
+    Native {
+        name: "transpose",
+        arity: Arity::Exact(1),
+        angle: Angle::None,
+        func: Func::Builtin(Builtin::Transpose),
+    },
+    Native {
+        name: "det",
+        arity: Arity::Exact(1),
+        angle: Angle::None,
+        func: Func::Builtin(Builtin::Det),
+    },
+    Native {
+        name: "inverse",
+        arity: Arity::Exact(1),
+        angle: Angle::None,
+        func: Func::Builtin(Builtin::Inverse),
+    },
+    // Solves `A x = b` for `x`
+    Native {
+        name: "linsolve",
+        arity: Arity::Exact(2),
+        angle: Angle::None,
+        func: Func::Builtin(Builtin::LinSolve),
+    },
+    Native {
+        name: "concat",
+        arity: Arity::AtLeast(1),
+        angle: Angle::None,
+        func: Func::Builtin(Builtin::Concat),
+    },
+    // `format("{:.2} USD", x)` fills each `{}` or `{:.N}` with the next value
+    Native {
+        name: "format",
+        arity: Arity::AtLeast(1),
+        angle: Angle::None,
+        func: Func::Builtin(Builtin::Format),
+    },
+    // Higher-order functions take function values such as `x -> $x * 2`
+    Native {
+        name: "map",
+        arity: Arity::Exact(2),
+        angle: Angle::None,
+        func: Func::Builtin(Builtin::Map),
+    },
+    Native {
+        name: "filter",
+        arity: Arity::Exact(2),
+        angle: Angle::None,
+        func: Func::Builtin(Builtin::Filter),
+    },
+    // `reduce(list, f, initial)` folds from the left, the first item is the
+    // initial value when none is given
+    Native {
+        name: "reduce",
+        arity: Arity::Between(2, 3),
+        angle: Angle::None,
+        func: Func::Builtin(Builtin::Reduce),
+    },
+    // Sorts by the number the function gives for each item
+    Native {
+        name: "sort_by",
+        arity: Arity::Exact(2),
+        angle: Angle::None,
+        func: Func::Builtin(Builtin::SortBy),
+    },
+    Native {
+        name: "compose",
+        arity: Arity::AtLeast(1),
+        angle: Angle::None,
+        func: Func::Builtin(Builtin::Compose),
+    },
+    // `solve(expr, x, guess)` finds where the expression in `$x` is zero
+    Native {
+        name: "solve",
+        arity: Arity::Exact(3),
+        angle: Angle::None,
+        func: Func::Builtin(Builtin::Solve),
+    },
+    // `integrate(expr, x, a, b)` integrates over `$x` from `a` to `b`
+    Native {
+        name: "integrate",
+        arity: Arity::Exact(4),
+        angle: Angle::None,
+        func: Func::Builtin(Builtin::Integrate),
+    },
+    // `diff(expr, x, at)` is the slope of the expression in `$x` at `at`
+    Native {
+        name: "diff",
+        arity: Arity::Exact(3),
+        angle: Angle::None,
+        func: Func::Builtin(Builtin::Diff),
+    },
+];
+
+fn round(args: &[f64]) -> Number {
+    let digits = args.get(1).copied().unwrap_or(0.0);
+    let scale = 10f64.powf(digits.trunc());
+    ((args[0] * scale).round() / scale).into()
+}
+
+fn mean(values: &[f64]) -> f64 {
+    values.iter().sum::<f64>() / values.len() as f64
+}
+
+// Sample variance, a single value has none
+fn variance(values: &[f64]) -> f64 {
+    if values.len() < 2 {
+        return f64::NAN;
+    }
+    let mean = mean(values);
+    let squares: f64 = values.iter().map(|x| (x - mean).powi(2)).sum();
+    squares / (values.len() - 1) as f64
+}
+
+// Interpolates linearly between the closest ranks, so the median of an even
+// number of values is the mean of the middle two
+fn quantile(values: &[f64], q: f64) -> f64 {
+    let mut sorted = values.to_vec();
+    sorted.sort_by(f64::total_cmp);
+    let rank = q * (sorted.len() - 1) as f64;
+    let (low, high) = (sorted[rank.floor() as usize], sorted[rank.ceil() as usize]);
+    low + (high - low) * rank.fract()
+}
+
+// The most common value, ties go to the smallest one
+fn mode(args: &[f64]) -> Number {
+    let mut sorted = args.to_vec();
+    sorted.sort_by(f64::total_cmp);
+    let mut best = (sorted[0], 0);
+    for run in sorted.chunk_by(|a, b| a == b) {
+        if run.len() > best.1 {
+            best = (run[0], run.len());
+        }
+    }
+    best.0.into()
+}
+
+// Both arguments of `nCr` and `nPr` must be whole numbers no less than zero,
+// anything else is a domain error
+fn counts(args: &[f64]) -> Option<(u64, u64)> {
+    let whole = |x: f64| (x.fract() == 0.0 && x >= 0.0 && x < u64::MAX as f64).then_some(x as u64);
+    Some((whole(args[0])?, whole(args[1])?))
+}
+
+fn choose(args: &[f64]) -> Number {
+    match counts(args) {
+        Some((n, k)) if k.min(n.saturating_sub(k)) > number::MAX_FACTORIAL => f64::INFINITY.into(),
+        Some((n, k)) => Number::Int(number::combinations(n, k)),
+        None => f64::NAN.into(),
+    }
+}
+
+fn permute(args: &[f64]) -> Number {
+    match counts(args) {
+        Some((n, k)) if k.min(n) > number::MAX_FACTORIAL => f64::INFINITY.into(),
+        Some((n, k)) => Number::Int(number::permutations(n, k)),
+        None => f64::NAN.into(),
+    }
+}
+
+pub fn lookup(name: &str) -> Option<&'static Native> {
+    NATIVES.iter().find(|native| native.name == name)
+}
+
+pub type ComplexFn = fn(&[Complex64]) -> Complex64;
+
+// Complex counterparts of the math functions, used for complex arguments and,
+// with the complex setting on, for real arguments outside the real domain
+const COMPLEX: &[(&str, ComplexFn)] = &[
+    ("sin", |z| z[0].sin()),
+    ("cos", |z| z[0].cos()),
+    ("tan", |z| z[0].tan()),
+    ("asin", |z| z[0].asin()),
+    ("acos", |z| z[0].acos()),
+    ("atan", |z| z[0].atan()),
+    ("sinh", |z| z[0].sinh()),
+    ("cosh", |z| z[0].cosh()),
+    ("tanh", |z| z[0].tanh()),
+    ("asinh", |z| z[0].asinh()),
+    ("acosh", |z| z[0].acosh()),
+    ("atanh", |z| z[0].atanh()),
+    ("sqrt", |z| z[0].sqrt()),
+    ("cbrt", |z| z[0].cbrt()),
+    ("exp", |z| z[0].exp()),
+    ("ln", |z| z[0].ln()),
+    ("log", |z| match z.get(1) {
+        Some(base) => z[0].ln() / base.ln(),
+        None => z[0].log10(),
+    }),
+    ("pow", |z| z[0].powc(z[1])),
+    ("abs", |z| z[0].norm().into()),
+    ("re", |z| z[0].re.into()),
+    ("im", |z| z[0].im.into()),
+    ("arg", |z| z[0].arg().into()),
+    ("conj", |z| z[0].conj()),
+];
+
+pub fn complex(name: &str) -> Option<ComplexFn> {
+    COMPLEX
+        .iter()
+        .find(|(function, _)| *function == name)
+        .map(|(_, func)| *func)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn call(name: &str, args: &[f64]) -> f64 {
+        let native = lookup(name).unwrap();
+        assert!(native.arity.accepts(args.len()));
+        let Func::Math(func) = native.func else {
+            panic!("{name} is not a math function");
+        };
+        func(args).to_f64()
+    }
+
+    #[test]
+    fn call_natives() {
+        assert_eq!(call("sqrt", &[16.]), 4.);
+        assert_eq!(call("round", &[2.567, 2.]), 2.57);
+        assert_eq!(call("round", &[2.5]), 3.);
+        assert_eq!(call("log", &[1000.]), 3.);
+        assert_eq!(call("log", &[8., 2.]), 3.);
+        assert_eq!(call("min", &[3., -1., 2.]), -1.);
+        assert_eq!(call("max", &[3., -1., 2.]), 3.);
+        assert_eq!(call("hypot", &[3., 4.]), 5.);
+    }
+
+    #[test]
+    fn statistics() {
+        let data = [2., 4., 4., 4., 5., 5., 7., 9.];
+        assert_eq!(call("sum", &data), 40.);
+        assert_eq!(call("mean", &data), 5.);
+        assert_eq!(call("median", &data), 4.5);
+        assert_eq!(call("median", &[3., 1., 2.]), 2.);
+        assert_eq!(call("mode", &data), 4.);
+        assert_eq!(call("mode", &[3., 1., 3., 1.]), 1.);
+        assert_eq!(call("variance", &data), 32. / 7.);
+        assert_eq!(call("stdev", &[1., 3.]), 2f64.sqrt());
+        assert!(call("stdev", &[1.]).is_nan());
+        assert_eq!(call("percentile", &[25., 1., 2., 3., 4., 5.]), 2.);
+        assert_eq!(call("percentile", &[90., 10., 20.]), 19.);
+        assert!(call("percentile", &[101., 1.]).is_nan());
+    }
+
+    #[test]
+    fn complex_counterparts() {
+        let z = Complex64::new(3., 4.);
+        assert_eq!(complex("abs").unwrap()(&[z]), 5.0.into());
+        assert_eq!(complex("conj").unwrap()(&[z]), Complex64::new(3., -4.));
+        assert_eq!(
+            complex("sqrt").unwrap()(&[(-4.0).into()]),
+            Complex64::new(0., 2.)
+        );
+        assert_eq!(call("arg", &[-1.]), std::f64::consts::PI);
+        assert_eq!(call("im", &[2.]), 0.);
+        assert!(complex("floor").is_none());
+    }
+
+    #[test]
+    fn combinatorics() {
+        assert_eq!(call("nCr", &[5., 2.]), 10.);
+        assert_eq!(call("choose", &[5., 6.]), 0.);
+        assert_eq!(call("nPr", &[5., 2.]), 20.);
+        assert_eq!(call("gamma", &[6.]), 120.);
+        assert!(call("nCr", &[5., 1.5]).is_nan());
+        assert!(call("nPr", &[-5., 2.]).is_nan());
+    }
+
+    #[test]
+    fn mod_modes() {
+        assert_eq!(ModMode::Trunc.apply(-7., 3.), -1.);
+        assert_eq!(ModMode::Floor.apply(-7., 3.), 2.);
+        assert_eq!(ModMode::Euclid.apply(-7., 3.), 2.);
+        assert_eq!(ModMode::Trunc.apply(7., -3.), 1.);
+        assert_eq!(ModMode::Floor.apply(7., -3.), -2.);
+        assert_eq!(ModMode::Euclid.apply(7., -3.), 1.);
+        assert_eq!(ModMode::Floor.apply(-7., -3.), -1.);
+        assert_eq!(ModMode::Euclid.apply(-7., -3.), 2.);
+        assert_eq!(call("rem", &[-7., 3.]), -1.);
+        assert_eq!(call("mod", &[-7., 3.]), 2.);
+    }
+
+    #[test]
+    fn angle_modes() {
+        assert_eq!(AngleMode::Deg.to_radians(180.), std::f64::consts::PI);
+        assert_eq!(AngleMode::Grad.from_radians(std::f64::consts::PI), 200.);
+        assert_eq!(AngleMode::Rad.to_radians(1.), 1.);
+        assert_eq!("grad".parse(), Ok(AngleMode::Grad));
+        assert!("turns".parse::<AngleMode>().is_err());
+    }
+
+    #[test]
+    fn arity() {
+        assert!(Arity::Between(1, 2).accepts(2));
+        assert!(!Arity::Exact(1).accepts(0));
+        assert!(Arity::AtLeast(1).accepts(5));
+        assert_eq!(Arity::AtLeast(1).to_string(), "at least 1 argument");
+        assert!(!Arity::Values(1).accepts(1));
+        assert_eq!(Arity::Values(1).to_string(), "at least 2 arguments");
+        assert_eq!(Arity::Exact(2).to_string(), "2 arguments");
+        assert!(lookup("sqr").is_none());
+    }
+}
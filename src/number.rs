@@ -0,0 +1,223 @@
+use num_bigint::BigInt;
+use num_complex::Complex64;
+use num_traits::{One, ToPrimitive};
+use std::fmt;
+use std::ops::Neg;
+
+// Largest integer below which every f64 integer is exact
+const MAX_SAFE_INTEGER: f64 = 9007199254740992.0;
+
+/// Largest factorial computed exactly, anything beyond it is reported as overflow.
+pub const MAX_FACTORIAL: u64 = 10_000;
+
+/// A numeric value, either a float, an exact integer or a complex number. Exact
+/// integers come out of factorials, combinatorics, bitwise operators and integer
+/// literals too large for a float, and stay exact through `+`, `-` and `*`.
+/// Complex numbers always have a non-zero imaginary part.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Number {
+    Float(f64),
+    Int(BigInt),
+    Complex(Complex64),
+}
+
+impl Number {
+    pub fn to_f64(&self) -> f64 {
+        match self {
+            Number::Float(num) => *num,
+            Number::Int(num) => num.to_f64().unwrap_or(f64::NAN),
+            Number::Complex(_) => f64::NAN,
+        }
+    }
+
+    /// Folds complex numbers without an imaginary part back into floats.
+    pub fn from_complex(num: Complex64) -> Number {
+        if num.im == 0.0 {
+            Number::Float(num.re)
+        } else {
+            Number::Complex(num)
+        }
+    }
+
+    pub fn to_complex(&self) -> Complex64 {
+        match self {
+            Number::Complex(num) => *num,
+            num => num.to_f64().into(),
+        }
+    }
+
+    pub fn is_complex(&self) -> bool {
+        matches!(self, Number::Complex(_))
+    }
+
+    fn exact(&self) -> Option<BigInt> {
+        match self {
+            Number::Int(num) => Some(num.clone()),
+            Number::Float(num) if num.fract() == 0.0 && num.abs() <= MAX_SAFE_INTEGER => {
+                Some(BigInt::from(*num as i64))
+            }
+            Number::Float(_) | Number::Complex(_) => None,
+        }
+    }
+
+    /// Applies `op` exactly when one side is already an exact integer and the
+    /// other can be represented as one.
+    pub fn exact_op(&self, other: &Number, op: fn(BigInt, BigInt) -> BigInt) -> Option<Number> {
+        if !matches!(self, Number::Int(_)) && !matches!(other, Number::Int(_)) {
+            return None;
+        }
+        Some(Number::Int(op(self.exact()?, other.exact()?)))
+    }
+}
+
+impl From<f64> for Number {
+    fn from(num: f64) -> Self {
+        Number::Float(num)
+    }
+}
+
+impl Neg for Number {
+    type Output = Number;
+
+    fn neg(self) -> Self::Output {
+        match self {
+            Number::Float(num) => Number::Float(-num),
+            Number::Int(num) => Number::Int(-num),
+            Number::Complex(num) => Number::Complex(-num),
+        }
+    }
+}
+
+impl Default for Number {
+    fn default() -> Self {
+        Number::Float(0.0)
+    }
+}
+
+impl fmt::Display for Number {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Number::Float(num) => write!(f, "{}", num),
+            Number::Int(num) => write!(f, "{}", num),
+            Number::Complex(num) if num.re == 0.0 => write!(f, "{}i", num.im),
+            Number::Complex(num) if num.im < 0.0 => write!(f, "{} - {}i", num.re, -num.im),
+            Number::Complex(num) => write!(f, "{} + {}i", num.re, num.im),
+        }
+    }
+}
+
+pub fn factorial(n: u64) -> BigInt {
+    (2..=n).fold(BigInt::one(), |acc, i| acc * i)
+}
+
+/// Ways to pick `k` of `n` items in order.
+pub fn permutations(n: u64, k: u64) -> BigInt {
+    if k > n {
+        return BigInt::ZERO;
+    }
+    (n - k + 1..=n).fold(BigInt::one(), |acc, i| acc * i)
+}
+
+/// Ways to pick `k` of `n` items in any order.
+pub fn combinations(n: u64, k: u64) -> BigInt {
+    if k > n {
+        return BigInt::ZERO;
+    }
+    let k = k.min(n - k);
+    (0..k).fold(BigInt::one(), |acc, i| acc * (n - i) / (i + 1))
+}
+
+// Lanczos approximation with g = 7, accurate to about 15 significant digits
+pub fn gamma(x: f64) -> f64 {
+    const COEFFICIENTS: [f64; 9] = [
+        0.999_999_999_999_809_9,
+        676.520_368_121_885_1,
+        -1_259.139_216_722_402_8,
+        771.323_428_777_653_1,
+        -176.615_029_162_140_6,
+        12.507_343_278_686_905,
+        -0.138_571_095_265_720_12,
+        9.984_369_578_019_572e-6,
+        1.505_632_735_149_311_6e-7,
+    ];
+    if x.fract() == 0.0 && x <= 0.0 {
+        return f64::NAN;
+    }
+    if x.fract() == 0.0 && x <= 171.0 {
+        return factorial(x as u64 - 1).to_f64().unwrap_or(f64::INFINITY);
+    }
+    if x < 0.5 {
+        return std::f64::consts::PI / ((std::f64::consts::PI * x).sin() * gamma(1.0 - x));
+    }
+    let x = x - 1.0;
+    let t = x + 7.5;
+    let sum = COEFFICIENTS[1..]
+        .iter()
+        .enumerate()
+        .fold(COEFFICIENTS[0], |sum, (i, c)| {
+            sum + c / (x + i as f64 + 1.0)
+        });
+    (2.0 * std::f64::consts::PI).sqrt() * t.powf(x + 0.5) * (-t).exp() * sum
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn exact_factorials() {
+        assert_eq!(
+            factorial(30).to_string(),
+            "265252859812191058636308480000000"
+        );
+        assert_eq!(factorial(0), BigInt::one());
+        assert_eq!(permutations(5, 2), BigInt::from(20));
+        assert_eq!(combinations(5, 2), BigInt::from(10));
+        assert_eq!(
+            combinations(100, 50).to_string(),
+            "100891344545564193334812497256"
+        );
+        assert_eq!(combinations(2, 5), BigInt::ZERO);
+    }
+
+    #[test]
+    fn exact_ops() {
+        let big = Number::Int(factorial(25));
+        let sum = big.exact_op(&Number::Float(1.0), |a, b| a + b).unwrap();
+        assert_eq!(sum.to_string(), "15511210043330985984000001");
+        assert!(big.exact_op(&Number::Float(0.5), |a, b| a + b).is_none());
+        assert!(
+            Number::Float(2.0)
+                .exact_op(&Number::Float(3.0), |a, b| a * b)
+                .is_none()
+        );
+    }
+
+    #[test]
+    fn complex_numbers() {
+        assert_eq!(
+            Number::from_complex(Complex64::new(3., 0.)),
+            Number::Float(3.)
+        );
+        assert_eq!(
+            Number::from_complex(Complex64::new(0., 2.)).to_string(),
+            "2i"
+        );
+        assert_eq!(
+            Number::Complex(Complex64::new(1., -2.5)).to_string(),
+            "1 - 2.5i"
+        );
+        assert_eq!(
+            (-Number::Complex(Complex64::new(1., 1.))).to_string(),
+            "-1 - 1i"
+        );
+        assert!(Number::Complex(Complex64::i()).to_f64().is_nan());
+    }
+
+    #[test]
+    fn gamma_values() {
+        assert!((gamma(5.0) - 24.0).abs() < 1e-9);
+        assert!((gamma(0.5) - std::f64::consts::PI.sqrt()).abs() < 1e-12);
+        assert!(gamma(-1.0).is_nan());
+    }
+}
@@ -0,0 +1,230 @@
+use miette::Result;
+
+/// The outcome of a numerical method: evaluating the expression may fail, and
+/// the method may give up with the reason why it did not converge.
+pub type Outcome = Result<Result<f64, &'static str>>;
+
+const NEWTON_STEPS: usize = 100;
+const BISECTION_STEPS: usize = 200;
+// Doublings of the search interval around the guess before giving up
+const BRACKET_STEPS: usize = 60;
+const SIMPSON_DEPTH: usize = 40;
+const SIMPSON_TOLERANCE: f64 = 1e-10;
+// Halvings of the step in the derivative extrapolation table
+const DIFF_STEPS: usize = 10;
+
+fn scale(x: f64) -> f64 {
+    x.abs().max(1.0)
+}
+
+/// A root of `f` near `guess`, by Newton's method falling back on bisection
+/// when it diverges or the slope vanishes.
+pub fn solve(f: &mut impl FnMut(f64) -> Result<f64>, guess: f64) -> Outcome {
+    let mut x = guess;
+    for _ in 0..NEWTON_STEPS {
+        let fx = f(x)?;
+        if fx == 0.0 {
+            return Ok(Ok(x));
+        }
+        let h = 1e-7 * scale(x);
+        let slope = (f(x + h)? - f(x - h)?) / (2.0 * h);
+        let next = x - fx / slope;
+        if !fx.is_finite() || !next.is_finite() {
+            break;
+        }
+        if (next - x).abs() <= 4.0 * f64::EPSILON * scale(x) {
+            return Ok(Ok(next));
+        }
+        x = next;
+    }
+    let Some((mut a, mut b)) = bracket(f, guess)? else {
+        return Ok(Err(
+            "no sign change was found near the guess, try another one",
+        ));
+    };
+    let (fa_start, fb_start) = (f(a)?, f(b)?);
+    let mut fa = fa_start;
+    for _ in 0..BISECTION_STEPS {
+        let m = a + (b - a) / 2.0;
+        if m == a || m == b {
+            break;
+        }
+        let fm = f(m)?;
+        if fm == 0.0 {
+            return Ok(Ok(m));
+        }
+        if fm.signum() == fa.signum() {
+            (a, fa) = (m, fm);
+        } else {
+            b = m;
+        }
+    }
+    let root = a + (b - a) / 2.0;
+    // The sign also flips across a pole such as the one of 1/x
+    if f(root)?.abs() > fa_start.abs().min(fb_start.abs()) {
+        return Ok(Err(
+            "the sign changes at a discontinuity rather than a root",
+        ));
+    }
+    Ok(Ok(root))
+}
+
+// Widens an interval around the guess until `f` changes sign in it
+fn bracket(f: &mut impl FnMut(f64) -> Result<f64>, guess: f64) -> Result<Option<(f64, f64)>> {
+    let f_guess = f(guess)?;
+    let mut step = 0.1 * scale(guess);
+    for _ in 0..BRACKET_STEPS {
+        let (lo, hi) = (guess - step, guess + step);
+        let (f_lo, f_hi) = (f(lo)?, f(hi)?);
+        for (a, fa, b, fb) in [
+            (lo, f_lo, guess, f_guess),
+            (guess, f_guess, hi, f_hi),
+            (lo, f_lo, hi, f_hi),
+        ] {
+            if fa.is_finite() && fb.is_finite() && fa.signum() != fb.signum() {
+                return Ok(Some((a, b)));
+            }
+        }
+        step *= 2.0;
+    }
+    Ok(None)
+}
+
+/// The integral of `f` from `a` to `b` by adaptive Simpson quadrature.
+pub fn integrate(f: &mut impl FnMut(f64) -> Result<f64>, a: f64, b: f64) -> Outcome {
+    if !a.is_finite() || !b.is_finite() {
+        return Ok(Err("the bounds of the integral must be finite"));
+    }
+    let m = a + (b - a) / 2.0;
+    let (fa, fm, fb) = (f(a)?, f(m)?, f(b)?);
+    let whole = (b - a) / 6.0 * (fa + 4.0 * fm + fb);
+    let tolerance = SIMPSON_TOLERANCE * scale(whole);
+    simpson(f, [a, m, b], [fa, fm, fb], whole, tolerance, SIMPSON_DEPTH)
+}
+
+// Splits the interval until Simpson's rule on both halves agrees with the whole
+fn simpson(
+    f: &mut impl FnMut(f64) -> Result<f64>,
+    [a, m, b]: [f64; 3],
+    [fa, fm, fb]: [f64; 3],
+    whole: f64,
+    tolerance: f64,
+    depth: usize,
+) -> Outcome {
+    if !whole.is_finite() {
+        return Ok(Err(
+            "the expression is not finite everywhere on the interval",
+        ));
+    }
+    let (left_m, right_m) = (a + (m - a) / 2.0, m + (b - m) / 2.0);
+    let (f_left, f_right) = (f(left_m)?, f(right_m)?);
+    let left = (m - a) / 6.0 * (fa + 4.0 * f_left + fm);
+    let right = (b - m) / 6.0 * (fm + 4.0 * f_right + fb);
+    let delta = left + right - whole;
+    if delta.abs() <= 15.0 * tolerance {
+        return Ok(Ok(left + right + delta / 15.0));
+    }
+    if depth == 0 {
+        return Ok(Err(
+            "the expression changes too quickly, try splitting the interval",
+        ));
+    }
+    let left = simpson(
+        f,
+        [a, left_m, m],
+        [fa, f_left, fm],
+        left,
+        tolerance / 2.0,
+        depth - 1,
+    )?;
+    let Ok(left) = left else {
+        return Ok(left);
+    };
+    let right = simpson(
+        f,
+        [m, right_m, b],
+        [fm, f_right, fb],
+        right,
+        tolerance / 2.0,
+        depth - 1,
+    )?;
+    Ok(right.map(|right| left + right))
+}
+
+/// The derivative of `f` at `x`, central differences refined by Richardson
+/// extrapolation. Steps are powers of two so nice points stay exact.
+pub fn diff(f: &mut impl FnMut(f64) -> Result<f64>, x: f64) -> Outcome {
+    let mut h = 2f64.powi(scale(x).log2().floor() as i32 - 3);
+    let mut table = vec![vec![0.0; DIFF_STEPS]; DIFF_STEPS];
+    let mut best = (f64::INFINITY, f64::NAN);
+    for i in 0..DIFF_STEPS {
+        table[0][i] = (f(x + h)? - f(x - h)?) / (2.0 * h);
+        let mut factor = 4.0;
+        for j in 1..=i {
+            table[j][i] = (table[j - 1][i] * factor - table[j - 1][i - 1]) / (factor - 1.0);
+            factor *= 4.0;
+            let error = (table[j][i] - table[j - 1][i])
+                .abs()
+                .max((table[j][i] - table[j - 1][i - 1]).abs());
+            if error <= best.0 {
+                best = (error, table[j][i]);
+            }
+        }
+        // Rounding error takes over once the estimates drift apart again
+        if i > 0 && (table[i][i] - table[i - 1][i - 1]).abs() >= 2.0 * best.0 {
+            break;
+        }
+        h /= 2.0;
+    }
+    let (error, derivative) = best;
+    if !derivative.is_finite() || error > 1e-6 * scale(derivative) {
+        return Ok(Err(
+            "the expression may not be differentiable at this point",
+        ));
+    }
+    Ok(Ok(derivative))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn roots() {
+        let root = solve(&mut |x| Ok(x * x - 2.0), 1.0).unwrap().unwrap();
+        assert!((root - 2f64.sqrt()).abs() < 1e-15);
+        let root = solve(&mut |x| Ok(x.cos() - x), 0.0).unwrap().unwrap();
+        assert!((root - 0.7390851332151607).abs() < 1e-15);
+        // Newton overshoots on the flat tails of atan, bisection still finds it
+        let root = solve(&mut |x| Ok((x - 3.0).atan()), 0.0).unwrap().unwrap();
+        assert!((root - 3.0).abs() < 1e-12);
+        assert!(solve(&mut |x| Ok(x * x + 1.0), 0.5).unwrap().is_err());
+        assert!(solve(&mut |x| Ok(1.0 / x), 0.5).unwrap().is_err());
+    }
+
+    #[test]
+    fn integrals() {
+        assert_eq!(integrate(&mut |x| Ok(x * x), 0.0, 3.0).unwrap(), Ok(9.0));
+        let area = integrate(&mut |x| Ok(x.sin()), 0.0, std::f64::consts::PI);
+        assert!((area.unwrap().unwrap() - 2.0).abs() < 1e-10);
+        let area = integrate(&mut |x| Ok((-x * x).exp()), -10.0, 10.0);
+        assert!((area.unwrap().unwrap() - std::f64::consts::PI.sqrt()).abs() < 1e-10);
+        assert_eq!(integrate(&mut |x| Ok(x), 2.0, 0.0).unwrap(), Ok(-2.0));
+        assert!(integrate(&mut |x| Ok(1.0 / x), 0.0, 1.0).unwrap().is_err());
+        assert!(
+            integrate(&mut |x| Ok(x), 0.0, f64::INFINITY)
+                .unwrap()
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn derivatives() {
+        assert_eq!(diff(&mut |x| Ok(x * x), 3.0).unwrap(), Ok(6.0));
+        let slope = diff(&mut |x| Ok(x.sin()), 1.0).unwrap().unwrap();
+        assert!((slope - 1f64.cos()).abs() < 1e-12);
+        let slope = diff(&mut |x| Ok(x.exp()), 10.0).unwrap().unwrap();
+        assert!((slope - 10f64.exp()).abs() < 1e-8 * 10f64.exp());
+        assert!(diff(&mut |x| Ok(x.cbrt()), 0.0).unwrap().is_err());
+    }
+}
@@ -1,8 +1,13 @@
+use crate::constants;
 use crate::lexer::{Lexer, Token, TokenKind};
-use miette::{Diagnostic, Result, SourceSpan};
+use crate::natives::{self, Func, Native};
+use crate::source::Source;
+use crate::units::{self, Unit};
+use miette::{Diagnostic, NamedSource, Result, SourceSpan};
+use num_bigint::BigInt;
+use num_traits::ToPrimitive;
 use std::fmt;
 use std::iter::Peekable;
-use std::str::FromStr;
 use thiserror::Error;
 
 pub trait Node: fmt::Display {}
@@ -17,46 +22,56 @@ impl<T: Node> Node for OperatorNode<T> {}
 
 impl<T: Node> fmt::Display for OperatorNode<T> {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        match &self.op.kind {
-            TokenKind::Plus => write!(
-                f,
-                "(+ {} {})",
-                self.left.as_ref().unwrap(),
-                self.right.as_ref().unwrap()
-            ),
-            TokenKind::Minus => write!(
-                f,
-                "(- {} {})",
-                self.left.as_ref().unwrap(),
-                self.right.as_ref().unwrap()
-            ),
-            TokenKind::Div => write!(
-                f,
-                "(/ {} {})",
-                self.left.as_ref().unwrap(),
-                self.right.as_ref().unwrap()
-            ),
-            TokenKind::Mult => write!(
-                f,
-                "(* {} {})",
-                self.left.as_ref().unwrap(),
-                self.right.as_ref().unwrap()
-            ),
-            TokenKind::Mod => write!(
-                f,
-                "(% {} {})",
-                self.left.as_ref().unwrap(),
-                self.right.as_ref().unwrap()
-            ),
+        let symbol = match &self.op.kind {
+            TokenKind::Plus => "+",
+            TokenKind::Minus => "-",
+            TokenKind::Div => "/",
+            TokenKind::IntDiv => "//",
+            TokenKind::Mult => "*",
+            TokenKind::MatMul => "@",
+            TokenKind::Mod => "%",
+            TokenKind::BitAnd => "&",
+            TokenKind::BitOr => "|",
+            TokenKind::Xor => "xor",
+            TokenKind::Shl => "<<",
+            TokenKind::Shr => ">>",
+            TokenKind::Of => "of",
+            TokenKind::Caret => "^",
             _ => unreachable!(),
-        }
+        };
+        write!(
+            f,
+            "({} {} {})",
+            symbol,
+            self.left.as_ref().unwrap(),
+            self.right.as_ref().unwrap()
+        )
     }
 }
 
 pub enum Nodes {
-    Number(u8, f64),
+    Number(SourceSpan, f64),
+    Int(SourceSpan, BigInt),
+    Imaginary(SourceSpan, f64),
+    Str(SourceSpan, String),
     Negative(Box<Nodes>),
     Positive(Box<Nodes>),
+    BitNot(Box<Nodes>),
+    Percent(Box<Nodes>),
+    Factorial(Box<Nodes>, SourceSpan),
+    Constant(SourceSpan, String, f64),
+    Variable(SourceSpan, String),
+    Assign(String, Box<Nodes>),
+    Import(SourceSpan, String),
+    Range(Box<Nodes>, Box<Nodes>, bool),
+    For(String, Box<Nodes>, Vec<Nodes>),
+    Lambda(SourceSpan, Vec<String>, Box<Nodes>),
+    Apply(Box<Nodes>, Vec<Nodes>, SourceSpan),
+    Call(SourceSpan, &'static Native, Vec<Nodes>),
+    List(SourceSpan, Vec<Nodes>),
+    Index(Box<Nodes>, Box<Nodes>, SourceSpan),
+    Quantity(SourceSpan, f64, Unit),
+    Convert(Box<Nodes>, SourceSpan, Unit),
     Operator(OperatorNode<Nodes>),
 }
 
@@ -65,20 +80,90 @@ impl fmt::Display for Nodes {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             Nodes::Number(_, num) => write!(f, "{}", num),
+            Nodes::Int(_, num) => write!(f, "{}", num),
+            Nodes::Imaginary(_, num) => write!(f, "{}i", num),
+            Nodes::Str(_, text) => write!(f, "{:?}", text),
             Nodes::Negative(node) => write!(f, "-{}", node),
             Nodes::Positive(node) => write!(f, "+{}", node),
+            Nodes::BitNot(node) => write!(f, "~{}", node),
+            Nodes::Percent(node) => write!(f, "{}%", node),
+            Nodes::Factorial(node, _) => write!(f, "{}!", node),
+            Nodes::Constant(_, name, _) => write!(f, "{}", name),
+            Nodes::Variable(_, name) => write!(f, "${}", name),
+            Nodes::Assign(name, node) => write!(f, "(= ${} {})", name, node),
+            Nodes::Import(_, path) => write!(f, "(import {:?})", path),
+            Nodes::Range(start, end, inclusive) => {
+                let symbol = if *inclusive { "..=" } else { ".." };
+                write!(f, "({} {} {})", symbol, start, end)
+            }
+            Nodes::For(name, iterable, body) => {
+                write!(f, "(for ${} {}", name, iterable)?;
+                for statement in body {
+                    write!(f, " {}", statement)?;
+                }
+                write!(f, ")")
+            }
+            Nodes::Lambda(_, params, body) => {
+                write!(f, "(->")?;
+                for param in params {
+                    write!(f, " {}", param)?;
+                }
+                write!(f, " {})", body)
+            }
+            Nodes::Apply(callee, args, _) => {
+                write!(f, "({}", callee)?;
+                for arg in args {
+                    write!(f, " {}", arg)?;
+                }
+                write!(f, ")")
+            }
+            Nodes::Call(_, native, args) => {
+                write!(f, "({}", native.name)?;
+                for arg in args {
+                    write!(f, " {}", arg)?;
+                }
+                write!(f, ")")
+            }
+            Nodes::List(_, items) => {
+                write!(f, "[")?;
+                for (i, item) in items.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, " ")?;
+                    }
+                    write!(f, "{}", item)?;
+                }
+                write!(f, "]")
+            }
+            Nodes::Index(list, index, _) => write!(f, "{}[{}]", list, index),
+            Nodes::Quantity(_, num, unit) => write!(f, "{} {}", num, unit),
+            Nodes::Convert(node, _, unit) => write!(f, "(to {} {})", node, unit),
             Nodes::Operator(op) => op.fmt(f),
         }
     }
 }
 
+// Implicit multiplication binds tighter than `*`, `/` and prefix minus, so
+// `1/2pi` is `1/(2*pi)` and `-2pi` is `-(2*pi)`
+const IMPLICIT_PRECEDENCE: u8 = 9;
+
+// Postfix operators bind tighter than any prefix operator, so `-15%` is `-(15%)`
+// and `-3!` is `-(3!)`
+const POSTFIX_PRECEDENCE: u8 = 10;
+
+// `^` binds tighter still, so `-2^2` is `-(2^2)` and `2pi^2` is `2*(pi^2)`
+const POWER_PRECEDENCE: u8 = 11;
+
 fn get_precedence(kind: &TokenKind) -> (u8, u8) {
     use TokenKind::*;
     match kind {
-        Plus => (3, 1),
-        Mod => (0, 1),
-        Minus => (3, 1),
-        Mult | Div => (0, 2),
+        To | DotDot | DotDotEq => (0, 1),
+        BitOr => (0, 2),
+        Xor => (0, 3),
+        BitAnd => (0, 4),
+        Shl | Shr => (0, 5),
+        Plus | Minus => (8, 6),
+        Mult | MatMul | Div | IntDiv | Mod | Of => (0, 7),
+        BitNot => (8, 0),
         _ => unreachable!(),
     }
 }
@@ -88,7 +173,7 @@ fn get_precedence(kind: &TokenKind) -> (u8, u8) {
 #[diagnostic(help("try closing brackets next time?"))]
 struct UnclosedBracket {
     #[source_code]
-    src: String,
+    src: NamedSource<String>,
     #[label("This bit here")]
     bad_bit: SourceSpan,
 }
@@ -98,51 +183,713 @@ struct UnclosedBracket {
 #[diagnostic(help("try entering a valid number"))]
 struct NumParseError {
     #[source_code]
-    src: String,
+    src: NamedSource<String>,
     #[label("This right here")]
     bad_bit: SourceSpan,
 }
 
+#[derive(Error, Debug, Diagnostic)]
+#[error("Failed to parse string!")]
+#[diagnostic(help(r#"close strings with a quote, and escape quotes inside them as \""#))]
+struct StrParseError {
+    #[source_code]
+    src: NamedSource<String>,
+    #[label("This string here")]
+    bad_bit: SourceSpan,
+}
+
+#[derive(Error, Debug, Diagnostic)]
+#[error("Unexpected trailing input!")]
+#[diagnostic(help("the expression ended before this, separate statements with ; or a new line"))]
+struct TrailingInput {
+    #[source_code]
+    src: NamedSource<String>,
+    #[label("This is left over")]
+    bad_bit: SourceSpan,
+}
+
 #[derive(Error, Debug, Diagnostic)]
 #[error("Unexpected Token!")]
 #[diagnostic(help("Enter help command for a list of valid operations"))]
 struct UnexpectedToken {
     #[source_code]
-    src: String,
+    src: NamedSource<String>,
     #[label("This token here")]
     bad_bit: SourceSpan,
 }
 
+#[derive(Error, Debug, Diagnostic)]
+#[error("Unknown unit!")]
+#[diagnostic(help("try a unit like m, km, s, h, kg, N or mph"))]
+struct UnknownUnit {
+    #[source_code]
+    src: NamedSource<String>,
+    #[label("This unit here")]
+    bad_bit: SourceSpan,
+}
+
+#[derive(Error, Debug, Diagnostic)]
+#[error("Unit power out of range!")]
+#[diagnostic(help("the power of each unit must stay between -128 and 127"))]
+struct UnitPowerRange {
+    #[source_code]
+    src: NamedSource<String>,
+    #[label("This unit here")]
+    bad_bit: SourceSpan,
+}
+
+#[derive(Error, Debug, Diagnostic)]
+#[error("Unknown name!")]
+#[diagnostic(help("type :consts for a list of constants, variables start with $"))]
+struct UnknownName {
+    #[source_code]
+    src: NamedSource<String>,
+    #[label("This name here")]
+    bad_bit: SourceSpan,
+}
+
+#[derive(Error, Debug, Diagnostic)]
+#[error("{name} expects {arity} but got {got}!")]
+#[diagnostic(help("check the number of arguments in this call"))]
+struct ArityMismatch {
+    #[source_code]
+    src: NamedSource<String>,
+    #[label("This call here")]
+    bad_bit: SourceSpan,
+    name: &'static str,
+    arity: natives::Arity,
+    got: usize,
+}
+
+#[derive(Error, Debug, Diagnostic)]
+#[error("{name} needs at least one value!")]
+#[diagnostic(help("list the values to work on, separated by commas"))]
+struct EmptyArguments {
+    #[source_code]
+    src: NamedSource<String>,
+    #[label("No values given here")]
+    bad_bit: SourceSpan,
+    name: &'static str,
+}
+
+#[derive(Error, Debug, Diagnostic)]
+#[error("Cannot assign to the constant {name}!")]
+#[diagnostic(help("pick another variable name"))]
+struct ConstantAssignment {
+    #[source_code]
+    src: NamedSource<String>,
+    #[label("This is a constant")]
+    bad_bit: SourceSpan,
+    name: String,
+}
+
 #[derive(Error, Debug, Diagnostic)]
 #[error("Error: Unexpected Eof!")]
 #[diagnostic(help("try writing complete expression(type help for more info)"))]
 struct UnexpectedEof {}
 
-pub fn parse(src: &str, lexer: &mut Peekable<Lexer>, prev_precedence: u8) -> Result<Nodes> {
+fn parse_number(literal: &str) -> Option<f64> {
+    let (radix, digits) = literal_digits(literal)?;
+    match BigInt::parse_bytes(digits.as_bytes(), radix) {
+        Some(num) => num.to_f64(),
+        // Fractions and exponents only come in decimal
+        None if radix == 10 => digits.parse().ok(),
+        None => None,
+    }
+}
+
+// Integer literals a float cannot hold exactly, such as 64-bit masks
+fn parse_integer(literal: &str) -> Option<BigInt> {
+    let (radix, digits) = literal_digits(literal)?;
+    let num = BigInt::parse_bytes(digits.as_bytes(), radix)?;
+    (num.bits() > 53).then_some(num)
+}
+
+fn literal_digits(literal: &str) -> Option<(u32, String)> {
+    let (radix, digits) = match literal.get(..2) {
+        Some("0x" | "0X") => (16, &literal[2..]),
+        Some("0b" | "0B") => (2, &literal[2..]),
+        Some("0o" | "0O") => (8, &literal[2..]),
+        _ => (10, literal),
+    };
+    // separators are only allowed between two digits, so `1__0`, `1_` and `0x_F` are rejected
+    let chars: Vec<char> = digits.chars().collect();
+    for (i, &ch) in chars.iter().enumerate() {
+        if ch == '_' {
+            let before = i.checked_sub(1).and_then(|j| chars.get(j));
+            let after = chars.get(i + 1);
+            if !before.is_some_and(|c| c.is_digit(radix))
+                || !after.is_some_and(|c| c.is_digit(radix))
+            {
+                return None;
+            }
+        }
+    }
+    Some((radix, digits.replace('_', "")))
+}
+
+// Unescapes a quoted string literal, `None` if it is unclosed or has an unknown
+// escape sequence
+fn parse_string(literal: &str) -> Option<String> {
+    let mut chars = literal.strip_prefix('"')?.chars();
+    let mut text = String::new();
+    while let Some(ch) = chars.next() {
+        match ch {
+            '"' => return chars.next().is_none().then_some(text),
+            '\\' => text.push(match chars.next()? {
+                'n' => '\n',
+                't' => '\t',
+                escaped @ ('"' | '\\') => escaped,
+                _ => return None,
+            }),
+            ch => text.push(ch),
+        }
+    }
+    None
+}
+
+// `%` is a percentage rather than a remainder when it is followed by another
+// operator, a closing bracket, a comma or the end of input. A sign stuck to a
+// number is the operand of a remainder instead, as in `7 % -3`
+fn is_percent(lexer: &Peekable<Lexer>) -> bool {
+    use TokenKind::*;
+    let mut ahead = lexer.clone().skip(1);
+    match ahead.next() {
+        None => true,
+        Some(sign) if matches!(sign.kind, Plus | Minus) => !ahead.next().is_some_and(|num| {
+            matches!(num.kind, Num(_)) && token_span(&num).offset() == sign.offset
+        }),
+        Some(token) => matches!(
+            token.kind,
+            Mult | Div
+                | Caret
+                | Mod
+                | BitAnd
+                | BitOr
+                | Xor
+                | Shl
+                | Shr
+                | To
+                | Of
+                | Rparen
+                | Rbrace
+                | DotDot
+                | DotDotEq
+                | Comma
+                | Semicolon
+                | Newline
+        ),
+    }
+}
+
+fn token_span(token: &Token) -> SourceSpan {
+    let len = match &token.kind {
+        TokenKind::Num(text) | TokenKind::Ident(text) | TokenKind::Str(text) => text.len(),
+        TokenKind::Shl | TokenKind::Shr | TokenKind::IntDiv | TokenKind::To | TokenKind::Of => 2,
+        TokenKind::DotDot | TokenKind::Arrow => 2,
+        TokenKind::Xor | TokenKind::Let | TokenKind::For | TokenKind::DotDotEq => 3,
+        TokenKind::Import => 6,
+        _ => 1,
+    };
+    ((token.offset - len), len).into()
+}
+
+fn is_unit(token: Option<&Token>) -> bool {
+    matches!(token, Some(Token { kind: TokenKind::Ident(name), .. }) if units::lookup(name).is_some())
+}
+
+// A unit name followed by a bracket is a function call instead, as in `2 min(1, 3)`
+fn starts_unit(lexer: &mut Peekable<Lexer>) -> bool {
+    is_unit(lexer.peek())
+        && !matches!(
+            lexer.clone().nth(1),
+            Some(Token {
+                kind: TokenKind::Lparen,
+                ..
+            })
+        )
+}
+
+fn parse_unit_factor(src: Source, lexer: &mut Peekable<Lexer>) -> Result<(Unit, SourceSpan)> {
+    let token = lexer.next().ok_or(UnexpectedEof {})?;
+    let TokenKind::Ident(name) = &token.kind else {
+        return Err(UnexpectedToken {
+            src: src.named(),
+            bad_bit: token_span(&token),
+        })?;
+    };
+    let span = token_span(&token);
+    let mut unit = units::lookup(name).ok_or(UnknownUnit {
+        src: src.named(),
+        bad_bit: span,
+    })?;
+    let mut end = token.offset;
+    if lexer
+        .next_if(|token| token.kind == TokenKind::Caret)
+        .is_some()
+    {
+        let negative = lexer
+            .next_if(|token| token.kind == TokenKind::Minus)
+            .is_some();
+        let token = lexer.next().ok_or(UnexpectedEof {})?;
+        let exp = match &token.kind {
+            TokenKind::Num(num) => num.parse::<i8>().ok(),
+            _ => None,
+        }
+        .ok_or(UnexpectedToken {
+            src: src.named(),
+            bad_bit: token_span(&token),
+        })?;
+        end = token.offset;
+        unit = unit
+            .powi(if negative { -exp } else { exp })
+            .ok_or(UnitPowerRange {
+                src: src.named(),
+                bad_bit: (span.offset(), end - span.offset()).into(),
+            })?;
+    }
+    Ok((unit, (span.offset(), end - span.offset()).into()))
+}
+
+// `*` and `/` only continue a unit when another unit follows, so `2 m / 4` divides by four
+fn parse_unit(src: Source, lexer: &mut Peekable<Lexer>) -> Result<(Unit, SourceSpan)> {
+    let (mut unit, span) = parse_unit_factor(src, lexer)?;
+    let mut end = span.offset() + span.len();
+    while let Some(token) = lexer.peek() {
+        let kind = token.kind.clone();
+        if !matches!(kind, TokenKind::Mult | TokenKind::Div)
+            || !is_unit(lexer.clone().nth(1).as_ref())
+        {
+            break;
+        }
+        lexer.next();
+        let (factor, factor_span) = parse_unit_factor(src, lexer)?;
+        end = factor_span.offset() + factor_span.len();
+        let combined = if kind == TokenKind::Mult {
+            unit.mul(&factor)
+        } else {
+            unit.div(&factor)
+        };
+        unit = combined.ok_or(UnitPowerRange {
+            src: src.named(),
+            bad_bit: (span.offset(), end - span.offset()).into(),
+        })?;
+    }
+    Ok((unit, (span.offset(), end - span.offset()).into()))
+}
+
+// Parses comma separated arguments after an opening bracket up to the matching
+// `close`, returning them with the offset just past the closing bracket
+fn parse_args(
+    src: Source,
+    lexer: &mut Peekable<Lexer>,
+    open: &Token,
+    close: TokenKind,
+) -> Result<(Vec<Nodes>, usize)> {
+    let unclosed = || UnclosedBracket {
+        src: src.named(),
+        bad_bit: (open.offset - 1, 1).into(),
+    };
+    let mut args = Vec::new();
+    if let Some(closing) = lexer.next_if(|token| token.kind == close) {
+        return Ok((args, closing.offset));
+    }
+    loop {
+        args.push(parse(src, lexer, 0)?);
+        let token = lexer.next().ok_or_else(unclosed)?;
+        match token.kind {
+            TokenKind::Comma => continue,
+            kind if kind == close => return Ok((args, token.offset)),
+            _ => Err(unclosed())?,
+        }
+    }
+}
+
+fn is_separator(token: &Token) -> bool {
+    matches!(token.kind, TokenKind::Semicolon | TokenKind::Newline)
+}
+
+/// Parses statements separated by `;` or new lines, each one has to end at a
+/// separator or the end of the input.
+pub fn parse_program(src: Source, lexer: &mut Peekable<Lexer>) -> Result<Vec<Nodes>> {
+    let mut statements = Vec::new();
+    loop {
+        while lexer.next_if(is_separator).is_some() {}
+        if lexer.peek().is_none() && !statements.is_empty() {
+            return Ok(statements);
+        }
+        statements.push(parse(src, lexer, 0)?);
+        match lexer.next() {
+            None => return Ok(statements),
+            Some(token) if is_separator(&token) => continue,
+            Some(token) => Err(trailing_input(src, lexer, token))?,
+        }
+    }
+}
+
+// Parses the statements of a `{ ... }` block after its opening brace, up to
+// and including the closing one
+fn parse_block(src: Source, lexer: &mut Peekable<Lexer>, open: &Token) -> Result<Vec<Nodes>> {
+    let unclosed = || UnclosedBracket {
+        src: src.named(),
+        bad_bit: (open.offset - 1, 1).into(),
+    };
+    let mut statements = Vec::new();
+    loop {
+        while lexer.next_if(is_separator).is_some() {}
+        statements.push(parse(src, lexer, 0)?);
+        let token = lexer.next().ok_or_else(unclosed)?;
+        match token.kind {
+            TokenKind::Rbrace => return Ok(statements),
+            _ if is_separator(&token) => {
+                while lexer.next_if(is_separator).is_some() {}
+                if lexer
+                    .next_if(|token| token.kind == TokenKind::Rbrace)
+                    .is_some()
+                {
+                    return Ok(statements);
+                }
+            }
+            _ => Err(trailing_input(src, lexer, token))?,
+        }
+    }
+}
+
+// `(a, b) -> ...` starts a lambda rather than a bracketed expression
+fn starts_lambda(lexer: &Peekable<Lexer>) -> bool {
+    let mut ahead = lexer.clone();
+    let mut expect_param = true;
+    loop {
+        match ahead.next().map(|token| token.kind) {
+            Some(TokenKind::Ident(_)) if expect_param => expect_param = false,
+            Some(TokenKind::Comma) if !expect_param => expect_param = true,
+            Some(TokenKind::Rparen) => {
+                return matches!(ahead.next(), Some(token) if token.kind == TokenKind::Arrow);
+            }
+            _ => return false,
+        }
+    }
+}
+
+// Parses the body of a lambda after its arrow, the whole lambda starts at `start`
+fn parse_lambda(
+    src: Source,
+    lexer: &mut Peekable<Lexer>,
+    start: usize,
+    params: Vec<Token>,
+) -> Result<Nodes> {
+    let mut names = Vec::with_capacity(params.len());
+    for param in params {
+        let TokenKind::Ident(name) = param.kind.clone() else {
+            unreachable!()
+        };
+        if constants::lookup(&name).is_some() {
+            Err(ConstantAssignment {
+                src: src.named(),
+                bad_bit: token_span(&param),
+                name: name.clone(),
+            })?;
+        }
+        names.push(name);
+    }
+    let body = parse(src, lexer, 0)?;
+    let span = span_from(src, lexer, start);
+    Ok(Nodes::Lambda(span, names, Box::new(body)))
+}
+
+// Spans from `start` to the end of what has been parsed so far
+fn span_from(src: Source, lexer: &mut Peekable<Lexer>, start: usize) -> SourceSpan {
+    let end = lexer
+        .peek()
+        .map_or(src.text.len(), |token| token_span(token).offset());
+    (start, src.text[start..end].trim_end().len()).into()
+}
+
+// Arguments of `solve($x * $x - 2, x, 1)` and the like, the expression becomes a
+// lambda of the variable after it, which is passed on by name as a string
+fn parse_bound_args(
+    src: Source,
+    lexer: &mut Peekable<Lexer>,
+    open: &Token,
+) -> Result<(Vec<Nodes>, usize)> {
+    let unclosed = || UnclosedBracket {
+        src: src.named(),
+        bad_bit: (open.offset - 1, 1).into(),
+    };
+    let start = lexer
+        .peek()
+        .map_or(open.offset, |token| token_span(token).offset());
+    let body = parse(src, lexer, 0)?;
+    let span = span_from(src, lexer, start);
+    let token = lexer.next().ok_or_else(unclosed)?;
+    match token.kind {
+        // Too few arguments, the arity check reports it
+        TokenKind::Rparen => {
+            return Ok((
+                vec![Nodes::Lambda(span, Vec::new(), Box::new(body))],
+                token.offset,
+            ));
+        }
+        TokenKind::Comma => (),
+        _ => Err(unclosed())?,
+    }
+    let var = lexer.next().ok_or(UnexpectedEof {})?;
+    let TokenKind::Ident(name) = var.kind.clone() else {
+        return Err(UnexpectedToken {
+            src: src.named(),
+            bad_bit: token_span(&var),
+        })?;
+    };
+    if constants::lookup(&name).is_some() {
+        Err(ConstantAssignment {
+            src: src.named(),
+            bad_bit: token_span(&var),
+            name: name.clone(),
+        })?;
+    }
+    let mut args = vec![
+        Nodes::Lambda(span, vec![name.clone()], Box::new(body)),
+        Nodes::Str(token_span(&var), name),
+    ];
+    let token = lexer.next().ok_or_else(unclosed)?;
+    match token.kind {
+        TokenKind::Rparen => Ok((args, token.offset)),
+        TokenKind::Comma => {
+            let (rest, end) = parse_args(src, lexer, open, TokenKind::Rparen)?;
+            args.extend(rest);
+            Ok((args, end))
+        }
+        _ => Err(unclosed())?,
+    }
+}
+
+// Spans from the first token the expression could not use to the end of the
+// statement
+fn trailing_input(src: Source, lexer: &mut Peekable<Lexer>, first: Token) -> TrailingInput {
+    let start = token_span(&first).offset();
+    let mut end = first.offset;
+    while let Some(token) = lexer.next_if(|token| !is_separator(token)) {
+        end = token.offset;
+    }
+    TrailingInput {
+        src: src.named(),
+        bad_bit: (start, end - start).into(),
+    }
+}
+
+pub fn parse(src: Source, lexer: &mut Peekable<Lexer>, prev_precedence: u8) -> Result<Nodes> {
     use TokenKind::*;
     let token = lexer.next().ok_or(UnexpectedEof {})?;
     let mut lhs = match token.kind {
-        Num(num) => Nodes::Number(
-            token.offset,
-            f64::from_str(&num).map_err(|_| NumParseError {
-                src: src.to_string(),
-                bad_bit: ((token.offset - num.len() as u8) as usize, num.len()).into(),
-            })?,
-        ),
+        Num(ref num) => {
+            let span = token_span(&token);
+            let number = parse_number(num).ok_or(NumParseError {
+                src: src.named(),
+                bad_bit: span,
+            })?;
+            // `3i` is an imaginary literal, the `i` has to follow the digits directly
+            let end = span.offset() + span.len();
+            let imaginary =
+                |token: &Token| token.kind == Ident("i".to_string()) && token.offset == end + 1;
+            if let Some(suffix) = lexer.next_if(imaginary) {
+                Nodes::Imaginary(
+                    (span.offset(), suffix.offset - span.offset()).into(),
+                    number,
+                )
+            } else if starts_unit(lexer) {
+                let (unit, unit_span) = parse_unit(src, lexer)?;
+                let len = unit_span.offset() + unit_span.len() - span.offset();
+                Nodes::Quantity((span.offset(), len).into(), number, unit)
+            } else if let Some(int) = parse_integer(num) {
+                Nodes::Int(span, int)
+            } else {
+                Nodes::Number(span, number)
+            }
+        }
+        Str(ref literal) => {
+            let span = token_span(&token);
+            let text = parse_string(literal).ok_or(StrParseError {
+                src: src.named(),
+                bad_bit: span,
+            })?;
+            Nodes::Str(span, text)
+        }
+        Ident(ref name) => {
+            let span = token_span(&token);
+            let unknown = || UnknownName {
+                src: src.named(),
+                bad_bit: span,
+            };
+            if lexer.next_if(|token| token.kind == Arrow).is_some() {
+                parse_lambda(src, lexer, span.offset(), vec![token.clone()])?
+            } else if let Some(lparen) = lexer.next_if(|token| token.kind == Lparen) {
+                let native = natives::lookup(name).ok_or_else(unknown)?;
+                let (args, end) = match native.func {
+                    Func::Builtin(builtin) if builtin.binds_variable() => {
+                        parse_bound_args(src, lexer, &lparen)?
+                    }
+                    _ => parse_args(src, lexer, &lparen, Rparen)?,
+                };
+                let span: SourceSpan = (span.offset(), end - span.offset()).into();
+                // A statistics call without any values, e.g. `mean()` or `percentile(50)`
+                if native.arity == natives::Arity::Values(args.len()) {
+                    Err(EmptyArguments {
+                        src: src.named(),
+                        bad_bit: span,
+                        name: native.name,
+                    })?;
+                }
+                if !native.arity.accepts(args.len()) {
+                    Err(ArityMismatch {
+                        src: src.named(),
+                        bad_bit: span,
+                        name: native.name,
+                        arity: native.arity,
+                        got: args.len(),
+                    })?;
+                }
+                Nodes::Call(span, native, args)
+            } else {
+                let value = constants::lookup(name).ok_or_else(unknown)?;
+                Nodes::Constant(span, name.clone(), value)
+            }
+        }
+        Var => {
+            let ident = lexer.next().ok_or(UnexpectedEof {})?;
+            let Ident(name) = ident.kind.clone() else {
+                return Err(UnexpectedToken {
+                    src: src.named(),
+                    bad_bit: token_span(&ident),
+                })?;
+            };
+            let start = token.offset - 1;
+            let span: SourceSpan = (start, ident.offset - start).into();
+            let constant = constants::lookup(&name);
+            if lexer.next_if(|token| token.kind == Equal).is_some() {
+                if constant.is_some() {
+                    Err(ConstantAssignment {
+                        src: src.named(),
+                        bad_bit: span,
+                        name: name.clone(),
+                    })?;
+                }
+                let expression = parse(src, lexer, 0)?;
+                Nodes::Assign(name, Box::new(expression))
+            } else if let Some(value) = constant {
+                Nodes::Constant(span, name, value)
+            } else {
+                Nodes::Variable(span, name)
+            }
+        }
+        // `let name = value` is the script form of `$name = value`
+        Let => {
+            let ident = lexer.next().ok_or(UnexpectedEof {})?;
+            let Ident(name) = ident.kind.clone() else {
+                return Err(UnexpectedToken {
+                    src: src.named(),
+                    bad_bit: token_span(&ident),
+                })?;
+            };
+            let equal = lexer.next().ok_or(UnexpectedEof {})?;
+            if equal.kind != Equal {
+                Err(UnexpectedToken {
+                    src: src.named(),
+                    bad_bit: token_span(&equal),
+                })?;
+            }
+            if constants::lookup(&name).is_some() {
+                Err(ConstantAssignment {
+                    src: src.named(),
+                    bad_bit: token_span(&ident),
+                    name: name.clone(),
+                })?;
+            }
+            let expression = parse(src, lexer, 0)?;
+            Nodes::Assign(name, Box::new(expression))
+        }
+        Import => {
+            let path = lexer.next().ok_or(UnexpectedEof {})?;
+            let Str(ref literal) = path.kind else {
+                return Err(UnexpectedToken {
+                    src: src.named(),
+                    bad_bit: token_span(&path),
+                })?;
+            };
+            let path_span = token_span(&path);
+            let path = parse_string(literal).ok_or(StrParseError {
+                src: src.named(),
+                bad_bit: path_span,
+            })?;
+            let start = token_span(&token).offset();
+            let end = path_span.offset() + path_span.len();
+            Nodes::Import((start, end - start).into(), path)
+        }
+        // `for i in 1..=12 { ... }` runs the block for every item, binding `$i`
+        For => {
+            let ident = lexer.next().ok_or(UnexpectedEof {})?;
+            let Ident(name) = ident.kind.clone() else {
+                return Err(UnexpectedToken {
+                    src: src.named(),
+                    bad_bit: token_span(&ident),
+                })?;
+            };
+            if constants::lookup(&name).is_some() {
+                Err(ConstantAssignment {
+                    src: src.named(),
+                    bad_bit: token_span(&ident),
+                    name: name.clone(),
+                })?;
+            }
+            // `to` lexes like `in`, only the latter reads right here
+            let within = lexer.next().ok_or(UnexpectedEof {})?;
+            let within_span = token_span(&within);
+            if within.kind != To || &src.text[within_span.offset()..within.offset] != "in" {
+                Err(UnexpectedToken {
+                    src: src.named(),
+                    bad_bit: within_span,
+                })?;
+            }
+            let iterable = parse(src, lexer, 0)?;
+            let open = lexer.next().ok_or(UnexpectedEof {})?;
+            if open.kind != Lbrace {
+                Err(UnexpectedToken {
+                    src: src.named(),
+                    bad_bit: token_span(&open),
+                })?;
+            }
+            let body = parse_block(src, lexer, &open)?;
+            Nodes::For(name, Box::new(iterable), body)
+        }
+        Lparen if starts_lambda(lexer) => {
+            let mut params = Vec::new();
+            while let Some(param) = lexer.next_if(|token| token.kind != Rparen) {
+                if param.kind != Comma {
+                    params.push(param);
+                }
+            }
+            lexer.next();
+            lexer.next();
+            parse_lambda(src, lexer, token.offset - 1, params)?
+        }
         Lparen => {
             let expression = parse(src, lexer, 0)?;
             let consumed = lexer.next().ok_or(UnclosedBracket {
-                src: src.to_string(),
-                bad_bit: ((token.offset - 1) as usize, 1).into(),
+                src: src.named(),
+                bad_bit: (token.offset - 1, 1).into(),
             })?;
             if consumed.kind != Rparen {
                 Err(UnclosedBracket {
-                    src: src.to_string(),
-                    bad_bit: ((token.offset - 1) as usize, 1).into(),
+                    src: src.named(),
+                    bad_bit: (token.offset - 1, 1).into(),
                 })?;
             }
             expression
         }
+        Lbracket => {
+            let (items, end) = parse_args(src, lexer, &token, Rbracket)?;
+            let start = token.offset - 1;
+            Nodes::List((start, end - start).into(), items)
+        }
         Minus => {
             let (prefix, _) = get_precedence(&TokenKind::Minus);
             let expression = parse(src, lexer, prefix)?;
@@ -153,17 +900,92 @@ pub fn parse(src: &str, lexer: &mut Peekable<Lexer>, prev_precedence: u8) -> Res
             let expression = parse(src, lexer, prefix)?;
             Nodes::Positive(Box::new(expression))
         }
+        BitNot => {
+            let (prefix, _) = get_precedence(&TokenKind::BitNot);
+            let expression = parse(src, lexer, prefix)?;
+            Nodes::BitNot(Box::new(expression))
+        }
         _ => {
+            // Illegal characters may take more than one byte
+            let len = src.text[..token.offset]
+                .chars()
+                .next_back()
+                .map_or(1, char::len_utf8);
             return Err(UnexpectedToken {
-                src: src.to_string(),
-                bad_bit: ((token.offset - 1) as usize, 1).into(),
+                src: src.named(),
+                bad_bit: (token.offset - len, len).into(),
             })?;
         }
     };
     while let Some(next_token) = lexer.peek() {
-        match next_token.kind {
-            Plus | Minus | Div | Mod | Mult => {
-                let (_, precedence) = get_precedence(&next_token.kind);
+        let kind = next_token.kind.clone();
+        match kind {
+            // `2(3 + 4)`, `2pi` and `2 sqrt(2)` multiply the number by what follows it
+            Lparen | Ident(_) if matches!(lhs, Nodes::Number(..) | Nodes::Int(..)) => {
+                if IMPLICIT_PRECEDENCE <= prev_precedence {
+                    break;
+                }
+                let op = Token {
+                    kind: Mult,
+                    offset: next_token.offset,
+                };
+                let right_node = parse(src, lexer, IMPLICIT_PRECEDENCE)?;
+                lhs = Nodes::Operator(OperatorNode {
+                    op,
+                    left: Some(Box::new(lhs)),
+                    right: Some(Box::new(right_node)),
+                });
+            }
+            // Calls a function value, as in `$f(2)` or `compose($f, $g)(2)`
+            Lparen
+                if matches!(
+                    lhs,
+                    Nodes::Variable(..)
+                        | Nodes::Lambda(..)
+                        | Nodes::Apply(..)
+                        | Nodes::Call(..)
+                        | Nodes::Index(..)
+                ) =>
+            {
+                if POSTFIX_PRECEDENCE <= prev_precedence {
+                    break;
+                }
+                let lparen = lexer.next().unwrap();
+                let (args, end) = parse_args(src, lexer, &lparen, Rparen)?;
+                let start = lparen.offset - 1;
+                lhs = Nodes::Apply(Box::new(lhs), args, (start, end - start).into());
+            }
+            Mod if is_percent(lexer) => {
+                if POSTFIX_PRECEDENCE <= prev_precedence {
+                    break;
+                }
+                lexer.next();
+                lhs = Nodes::Percent(Box::new(lhs));
+            }
+            Lbracket => {
+                if POSTFIX_PRECEDENCE <= prev_precedence {
+                    break;
+                }
+                let lbracket = lexer.next().unwrap();
+                let index = parse(src, lexer, 0)?;
+                let start = lbracket.offset - 1;
+                let rbracket = lexer.next_if(|token| token.kind == Rbracket);
+                let end = rbracket.map(|token| token.offset).ok_or(UnclosedBracket {
+                    src: src.named(),
+                    bad_bit: (start, 1).into(),
+                })?;
+                lhs = Nodes::Index(Box::new(lhs), Box::new(index), (start, end - start).into());
+            }
+            Bang => {
+                if POSTFIX_PRECEDENCE <= prev_precedence {
+                    break;
+                }
+                let bang = lexer.next().unwrap();
+                lhs = Nodes::Factorial(Box::new(lhs), token_span(&bang));
+            }
+            Plus | Minus | Div | IntDiv | Mod | Mult | MatMul | BitAnd | BitOr | Xor | Shl
+            | Shr | Of => {
+                let (_, precedence) = get_precedence(&kind);
                 if precedence <= prev_precedence {
                     break;
                 } else {
@@ -177,6 +999,37 @@ pub fn parse(src: &str, lexer: &mut Peekable<Lexer>, prev_precedence: u8) -> Res
                     lhs = Nodes::Operator(op_node);
                 }
             }
+            // Right associative, `2^3^2` is `2^(3^2)`
+            Caret => {
+                if POWER_PRECEDENCE <= prev_precedence {
+                    break;
+                }
+                let op = lexer.next().unwrap();
+                let right_node = parse(src, lexer, POWER_PRECEDENCE - 1)?;
+                lhs = Nodes::Operator(OperatorNode {
+                    op,
+                    left: Some(Box::new(lhs)),
+                    right: Some(Box::new(right_node)),
+                });
+            }
+            DotDot | DotDotEq => {
+                let (_, precedence) = get_precedence(&kind);
+                if precedence <= prev_precedence {
+                    break;
+                }
+                lexer.next();
+                let end = parse(src, lexer, precedence)?;
+                lhs = Nodes::Range(Box::new(lhs), Box::new(end), kind == DotDotEq);
+            }
+            To => {
+                let (_, precedence) = get_precedence(&kind);
+                if precedence <= prev_precedence {
+                    break;
+                }
+                lexer.next();
+                let (unit, span) = parse_unit(src, lexer)?;
+                lhs = Nodes::Convert(Box::new(lhs), span, unit);
+            }
             _ => break,
         }
     }
@@ -189,7 +1042,7 @@ mod test {
     #[test]
     fn parse_int() {
         let source = "1";
-        let lexer = Lexer::new(&source);
+        let lexer = Lexer::new(source);
         let parsed: Nodes = parse(lexer.source, &mut lexer.peekable(), 0).unwrap();
         assert_eq!(parsed.to_string(), "1");
     }
@@ -197,15 +1050,441 @@ mod test {
     #[test]
     fn parse_expr() {
         let source = "3 * 2 + 1";
-        let lexer = Lexer::new(&source);
+        let lexer = Lexer::new(source);
         let parsed: Nodes = parse(lexer.source, &mut lexer.peekable(), 0).unwrap();
         assert_eq!(parsed.to_string(), "(+ (* 3 2) 1)");
     }
 
+    #[test]
+    fn parse_num_literal_forms() {
+        let source = "1e9 + 6.02e23 + 2.5E-3 + 0xFF + 0b1010 + 0o755 + 1_000_000";
+        let lexer = Lexer::new(source);
+        let parsed: Nodes = parse(lexer.source, &mut lexer.peekable(), 0).unwrap();
+        assert_eq!(
+            parsed.to_string(),
+            "(+ (+ (+ (+ (+ (+ 1000000000 602000000000000000000000) 0.0025) 255) 10) 493) 1000000)"
+        );
+    }
+
+    #[test]
+    fn parse_malformed_literals() {
+        for (source, span) in [
+            ("1 + 0xZZ", (4, 4)),
+            ("0b102", (0, 5)),
+            ("1__000", (0, 6)),
+            ("2 * 10_", (4, 3)),
+            ("0x", (0, 2)),
+            ("1.2.3", (0, 5)),
+        ] {
+            let lexer = Lexer::new(source);
+            let Err(err) = parse(lexer.source, &mut lexer.peekable(), 0) else {
+                panic!("{source} should not parse");
+            };
+            let label = err.labels().unwrap().next().unwrap();
+            assert_eq!((label.offset(), label.len()), span, "{source}");
+        }
+    }
+
+    #[test]
+    fn parse_bitwise_precedence() {
+        let source = "1 | 2 xor 3 & 4 << 1 + 2";
+        let lexer = Lexer::new(source);
+        let parsed: Nodes = parse(lexer.source, &mut lexer.peekable(), 0).unwrap();
+        assert_eq!(parsed.to_string(), "(| 1 (xor 2 (& 3 (<< 4 (+ 1 2)))))");
+    }
+
+    #[test]
+    fn parse_bitnot_pref() {
+        let source = "~1 & 6 % 4";
+        let lexer = Lexer::new(source);
+        let parsed: Nodes = parse(lexer.source, &mut lexer.peekable(), 0).unwrap();
+        assert_eq!(parsed.to_string(), "(& ~1 (% 6 4))");
+    }
+
+    #[test]
+    fn parse_quantities() {
+        let source = "5 kg * 9.81 m/s^2 + 2 m / 4 to N";
+        let lexer = Lexer::new(source);
+        let parsed: Nodes = parse(lexer.source, &mut lexer.peekable(), 0).unwrap();
+        assert_eq!(
+            parsed.to_string(),
+            "(to (+ (* 5 kg 9.81 m/s^2) (/ 2 m 4)) N)"
+        );
+    }
+
+    #[test]
+    fn parse_unknown_conversion_unit() {
+        let source = "3 m to furlong";
+        let lexer = Lexer::new(source);
+        let Err(err) = parse(lexer.source, &mut lexer.peekable(), 0) else {
+            panic!("furlong is not a unit");
+        };
+        let label = err.labels().unwrap().next().unwrap();
+        assert_eq!((label.offset(), label.len()), (7, 7));
+    }
+
+    #[test]
+    fn parse_percent_or_mod() {
+        for (source, expected) in [
+            ("200 + 15%", "(+ 200 15%)"),
+            ("(200 * 15%) - 1", "(- (* 200 15%) 1)"),
+            ("15% of 200", "(of 15% 200)"),
+            ("-15%", "-15%"),
+            ("7 % 3", "(% 7 3)"),
+            ("7 % (3)", "(% 7 3)"),
+            ("7 % -3", "(% 7 -3)"),
+            ("-7 % -3", "(% -7 -3)"),
+            ("7 % -3 - 1", "(- (% 7 -3) 1)"),
+            ("200 + 15% + 5", "(+ (+ 200 15%) 5)"),
+            ("200 + 15% - 5", "(- (+ 200 15%) 5)"),
+            ("50% - 1", "(- 50% 1)"),
+            ("7 % - 3", "(- 7% 3)"),
+            ("max(15%, 1)", "(max 15% 1)"),
+        ] {
+            let lexer = Lexer::new(source);
+            let parsed: Nodes = parse(lexer.source, &mut lexer.peekable(), 0).unwrap();
+            assert_eq!(parsed.to_string(), expected, "{source}");
+        }
+    }
+
+    #[test]
+    fn parse_lists() {
+        for (source, expected) in [
+            ("[1, 2, 3]", "[1 2 3]"),
+            ("[]", "[]"),
+            ("[1, [2, 3]] * 2", "(* [1 [2 3]] 2)"),
+            ("$xs[0] + 1", "(+ $xs[0] 1)"),
+            ("-$xs[1 + 1]", "-$xs[(+ 1 1)]"),
+            ("[[1, 2]][0][1]", "[[1 2]][0][1]"),
+            ("sum([1, 2])", "(sum [1 2])"),
+            ("[[1, 2]] @ [3, 4] * 2", "(* (@ [[1 2]] [3 4]) 2)"),
+        ] {
+            let lexer = Lexer::new(source);
+            let parsed: Nodes = parse(lexer.source, &mut lexer.peekable(), 0).unwrap();
+            assert_eq!(parsed.to_string(), expected, "{source}");
+        }
+        for (source, span) in [("[1, 2", (0, 1)), ("$xs[0", (3, 1))] {
+            let lexer = Lexer::new(source);
+            let Err(err) = parse(lexer.source, &mut lexer.peekable(), 0) else {
+                panic!("{source} should not parse");
+            };
+            let label = err.labels().unwrap().next().unwrap();
+            assert_eq!((label.offset(), label.len()), span, "{source}");
+        }
+    }
+
+    #[test]
+    fn parse_imaginary() {
+        for (source, expected) in [
+            ("3i", "3i"),
+            ("2 + 0.5i", "(+ 2 0.5i)"),
+            ("-1e3i", "-1000i"),
+            ("2 im(1)", "(* 2 (im 1))"),
+        ] {
+            let lexer = Lexer::new(source);
+            let parsed: Nodes = parse(lexer.source, &mut lexer.peekable(), 0).unwrap();
+            assert_eq!(parsed.to_string(), expected, "{source}");
+        }
+        let source = "3 i";
+        let lexer = Lexer::new(source);
+        assert!(parse(lexer.source, &mut lexer.peekable(), 0).is_err());
+    }
+
+    #[test]
+    fn parse_statements() {
+        for (source, expected) in [
+            ("1; 2", vec!["1", "2"]),
+            ("$x = 2\n$x * 3\n", vec!["(= $x 2)", "(* $x 3)"]),
+            (";;1;\n\n", vec!["1"]),
+            ("[1,\n 2]; 3", vec!["[1 2]", "3"]),
+        ] {
+            let lexer = Lexer::new(source);
+            let parsed = parse_program(lexer.source, &mut lexer.peekable()).unwrap();
+            let parsed: Vec<_> = parsed.iter().map(Nodes::to_string).collect();
+            assert_eq!(parsed, expected, "{source:?}");
+        }
+        for (source, span) in [
+            ("1 + 2 3", (6, 1)),
+            ("1; 2)", (4, 1)),
+            ("1 + 2 ) * (4; 5", (6, 6)),
+            ("3 4 5 \n6", (2, 3)),
+        ] {
+            let lexer = Lexer::new(source);
+            let Err(err) = parse_program(lexer.source, &mut lexer.peekable()) else {
+                panic!("{source} should not parse");
+            };
+            let label = err.labels().unwrap().next().unwrap();
+            assert_eq!((label.offset(), label.len()), span, "{source}");
+        }
+        for source in ["", ";", "\n"] {
+            let lexer = Lexer::new(source);
+            assert!(parse_program(lexer.source, &mut lexer.peekable()).is_err());
+        }
+    }
+
+    #[test]
+    fn parse_scripts() {
+        let source = "# rates\nlet rate = 0.05 # yearly\nimport \"lib/tax.nex\"\n$rate * 2";
+        let lexer = Lexer::new(source);
+        let parsed = parse_program(lexer.source, &mut lexer.peekable()).unwrap();
+        let parsed: Vec<_> = parsed.iter().map(Nodes::to_string).collect();
+        assert_eq!(
+            parsed,
+            vec!["(= $rate 0.05)", r#"(import "lib/tax.nex")"#, "(* $rate 2)"]
+        );
+        let Nodes::Import(span, _) =
+            parse(source.into(), &mut Lexer::new("import \"a\"").peekable(), 0).unwrap()
+        else {
+            panic!("expected an import");
+        };
+        assert_eq!((span.offset(), span.len()), (0, 10));
+        for (source, span) in [
+            ("let 2 = 3", (4, 1)),
+            ("let x 3", (6, 1)),
+            ("let pi = 3", (4, 2)),
+            ("import x", (7, 1)),
+        ] {
+            let lexer = Lexer::new(source);
+            let Err(err) = parse(lexer.source, &mut lexer.peekable(), 0) else {
+                panic!("{source} should not parse");
+            };
+            let label = err.labels().unwrap().next().unwrap();
+            assert_eq!((label.offset(), label.len()), span, "{source}");
+        }
+    }
+
+    #[test]
+    fn parse_loops() {
+        for (source, expected) in [
+            ("1..=12", "(..= 1 12)"),
+            ("0..$n + 1", "(.. 0 (+ $n 1))"),
+            ("for i in 1..4 { $i * 2 }", "(for $i (.. 1 4) (* $i 2))"),
+            (
+                "for i in [1, 2] {\n  $t = $t + $i\n  $t\n}",
+                "(for $i [1 2] (= $t (+ $t $i)) $t)",
+            ),
+        ] {
+            let lexer = Lexer::new(source);
+            let parsed: Nodes = parse(lexer.source, &mut lexer.peekable(), 0).unwrap();
+            assert_eq!(parsed.to_string(), expected, "{source}");
+        }
+        for (source, span) in [
+            ("for 2 in x { 1 }", (4, 1)),
+            ("for pi in 1..2 { 1 }", (4, 2)),
+            ("for i to 1..2 { 1 }", (6, 2)),
+            ("for i in 1..2 1", (14, 1)),
+            ("for i in 1..2 { 1 2 }", (18, 3)),
+            ("for i in 1..2 { 1", (14, 1)),
+        ] {
+            let lexer = Lexer::new(source);
+            let Err(err) = parse(lexer.source, &mut lexer.peekable(), 0) else {
+                panic!("{source} should not parse");
+            };
+            let label = err.labels().unwrap().next().unwrap();
+            assert_eq!((label.offset(), label.len()), span, "{source}");
+        }
+    }
+
+    #[test]
+    fn parse_powers() {
+        for (source, expected) in [
+            ("2^3", "(^ 2 3)"),
+            ("2^3^2", "(^ 2 (^ 3 2))"),
+            ("-2^2", "-(^ 2 2)"),
+            ("2^-1", "(^ 2 -1)"),
+            ("2pi^2", "(* 2 (^ pi 2))"),
+            ("3 * $x^2 + 1", "(+ (* 3 (^ $x 2)) 1)"),
+        ] {
+            let lexer = Lexer::new(source);
+            let parsed: Nodes = parse(lexer.source, &mut lexer.peekable(), 0).unwrap();
+            assert_eq!(parsed.to_string(), expected, "{source}");
+        }
+    }
+
+    #[test]
+    fn parse_lambdas() {
+        for (source, expected) in [
+            ("x -> $x * 2", "(-> x (* $x 2))"),
+            ("x -> $x^2", "(-> x (^ $x 2))"),
+            ("(a, b) -> $a + $b", "(-> a b (+ $a $b))"),
+            ("() -> 1", "(-> 1)"),
+            ("map([1], x -> $x)", "(map [1] (-> x $x))"),
+            ("$f(1, 2) * 3", "(* ($f 1 2) 3)"),
+            ("(x -> $x)(2)", "((-> x $x) 2)"),
+            ("compose($f, $g)(2)", "((compose $f $g) 2)"),
+            ("-$fs[0](2)", "-($fs[0] 2)"),
+            (
+                "solve($x * $x - 2, x, 1)",
+                r#"(solve (-> x (- (* $x $x) 2)) "x" 1)"#,
+            ),
+        ] {
+            let lexer = Lexer::new(source);
+            let parsed: Nodes = parse(lexer.source, &mut lexer.peekable(), 0).unwrap();
+            assert_eq!(parsed.to_string(), expected, "{source}");
+        }
+        let lexer = Lexer::new("$f = x -> $x  ; 1");
+        let Nodes::Assign(_, lambda) = parse(lexer.source, &mut lexer.peekable(), 0).unwrap()
+        else {
+            panic!("expected an assignment");
+        };
+        let Nodes::Lambda(span, ..) = *lambda else {
+            panic!("expected a lambda");
+        };
+        assert_eq!((span.offset(), span.len()), (5, 7));
+        for (source, span) in [
+            ("e -> $e", (0, 1)),
+            ("(x, pi) -> 1", (4, 2)),
+            ("diff($x, pi, 1)", (9, 2)),
+            ("solve($x, 1, 2)", (10, 1)),
+            ("solve($x)", (0, 9)),
+        ] {
+            let lexer = Lexer::new(source);
+            let Err(err) = parse(lexer.source, &mut lexer.peekable(), 0) else {
+                panic!("{source} should not parse");
+            };
+            let label = err.labels().unwrap().next().unwrap();
+            assert_eq!((label.offset(), label.len()), span, "{source}");
+        }
+    }
+
+    #[test]
+    fn parse_strings() {
+        for (source, expected) in [
+            (r#""total: " + "5""#, r#"(+ "total: " "5")"#),
+            (r#""tab\tquote\"""#, r#""tab\tquote\"""#),
+            (r#"format("{:.2} USD", 3)"#, r#"(format "{:.2} USD" 3)"#),
+        ] {
+            let lexer = Lexer::new(source);
+            let parsed: Nodes = parse(lexer.source, &mut lexer.peekable(), 0).unwrap();
+            assert_eq!(parsed.to_string(), expected, "{source}");
+        }
+        for (source, span) in [(r#"1 + "abc"#, (4, 4)), (r#""bad \q""#, (0, 8))] {
+            let lexer = Lexer::new(source);
+            let Err(err) = parse(lexer.source, &mut lexer.peekable(), 0) else {
+                panic!("{source} should not parse");
+            };
+            let label = err.labels().unwrap().next().unwrap();
+            assert_eq!((label.offset(), label.len()), span, "{source}");
+        }
+    }
+
+    #[test]
+    fn parse_factorial() {
+        for (source, expected) in [
+            ("3!", "3!"),
+            ("-3!", "-3!"),
+            ("2 * 3! + 1", "(+ (* 2 3!) 1)"),
+            ("(1 + 2)!!", "(+ 1 2)!!"),
+            ("2 pi!", "(* 2 pi!)"),
+        ] {
+            let lexer = Lexer::new(source);
+            let parsed: Nodes = parse(lexer.source, &mut lexer.peekable(), 0).unwrap();
+            assert_eq!(parsed.to_string(), expected, "{source}");
+        }
+    }
+
+    #[test]
+    fn parse_constants_and_variables() {
+        let source = "$area = pi * $r * $r + e";
+        let lexer = Lexer::new(source);
+        let parsed: Nodes = parse(lexer.source, &mut lexer.peekable(), 0).unwrap();
+        assert_eq!(parsed.to_string(), "(= $area (+ (* (* pi $r) $r) e))");
+    }
+
+    #[test]
+    fn parse_constant_assignment() {
+        for (source, span) in [("$pi = 3", (0, 3)), ("1 + $tau = 2", (4, 4))] {
+            let lexer = Lexer::new(source);
+            let Err(err) = parse(lexer.source, &mut lexer.peekable(), 0) else {
+                panic!("{source} should not parse");
+            };
+            let label = err.labels().unwrap().next().unwrap();
+            assert_eq!((label.offset(), label.len()), span, "{source}");
+        }
+    }
+
+    #[test]
+    fn parse_unknown_name() {
+        let source = "2 * pie";
+        let lexer = Lexer::new(source);
+        let Err(err) = parse(lexer.source, &mut lexer.peekable(), 0) else {
+            panic!("pie is not a constant");
+        };
+        let label = err.labels().unwrap().next().unwrap();
+        assert_eq!((label.offset(), label.len()), (4, 3));
+    }
+
+    #[test]
+    fn parse_calls() {
+        let source = "max(1, sqrt(4) * 2, round(2.5)) + log(8, 2) - pow(2, 3)";
+        let lexer = Lexer::new(source);
+        let parsed: Nodes = parse(lexer.source, &mut lexer.peekable(), 0).unwrap();
+        assert_eq!(
+            parsed.to_string(),
+            "(- (+ (max 1 (* (sqrt 4) 2) (round 2.5)) (log 8 2)) (pow 2 3))"
+        );
+    }
+
+    #[test]
+    fn parse_call_errors() {
+        for (source, span) in [
+            ("1 + sqrt(1, 2)", (4, 10)),
+            ("min()", (0, 5)),
+            ("sqrt(4", (4, 1)),
+            ("frobnicate(1)", (0, 10)),
+        ] {
+            let lexer = Lexer::new(source);
+            let Err(err) = parse(lexer.source, &mut lexer.peekable(), 0) else {
+                panic!("{source} should not parse");
+            };
+            let label = err.labels().unwrap().next().unwrap();
+            assert_eq!((label.offset(), label.len()), span, "{source}");
+        }
+    }
+
+    #[test]
+    fn parse_empty_variadic_calls() {
+        for (source, message) in [
+            ("mean()", "mean needs at least one value!"),
+            ("min()", "min needs at least one value!"),
+            ("percentile(50)", "percentile needs at least one value!"),
+            ("concat()", "concat expects at least 1 argument but got 0!"),
+            (
+                "compose()",
+                "compose expects at least 1 argument but got 0!",
+            ),
+        ] {
+            let lexer = Lexer::new(source);
+            let Err(err) = parse(lexer.source, &mut lexer.peekable(), 0) else {
+                panic!("{source} should not parse");
+            };
+            assert!(err.to_string().ends_with(message), "{source}: {err}");
+        }
+    }
+
+    #[test]
+    fn parse_implicit_mult() {
+        for (source, expected) in [
+            ("2(3 + 4)", "(* 2 (+ 3 4))"),
+            ("3pi", "(* 3 pi)"),
+            ("1/2pi", "(/ 1 (* 2 pi))"),
+            ("2pi/3", "(/ (* 2 pi) 3)"),
+            ("-2pi", "-(* 2 pi)"),
+            ("2 sqrt(4) + 1", "(+ (* 2 (sqrt 4)) 1)"),
+            ("2 min(1, 3)", "(* 2 (min 1 3))"),
+            ("2 min", "2 min"),
+            ("2 * 3(4)", "(* 2 (* 3 4))"),
+        ] {
+            let lexer = Lexer::new(source);
+            let parsed: Nodes = parse(lexer.source, &mut lexer.peekable(), 0).unwrap();
+            assert_eq!(parsed.to_string(), expected, "{source}");
+        }
+    }
+
     #[test]
     fn parse_negative_pref() {
         let source = "-3 + 2";
-        let lexer = Lexer::new(&source);
+        let lexer = Lexer::new(source);
         let parsed: Nodes = parse(lexer.source, &mut lexer.peekable(), 0).unwrap();
         assert_eq!(parsed.to_string(), "(+ -3 2)");
     }
@@ -213,7 +1492,7 @@ mod test {
     #[test]
     fn parse_pos_pref() {
         let source = "+(-3 + 2)";
-        let lexer = Lexer::new(&source);
+        let lexer = Lexer::new(source);
         let parsed: Nodes = parse(lexer.source, &mut lexer.peekable(), 0).unwrap();
         assert_eq!(parsed.to_string(), "+(+ -3 2)");
     }
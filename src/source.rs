@@ -0,0 +1,50 @@
+use miette::NamedSource;
+
+/// Source text together with the name diagnostics show for it, the path of a
+/// script or `<input>` for a line typed into the REPL.
+#[derive(Clone, Copy, Debug)]
+pub struct Source<'a> {
+    pub name: &'a str,
+    pub text: &'a str,
+}
+
+impl<'a> Source<'a> {
+    pub fn new(name: &'a str, text: &'a str) -> Self {
+        Self { name, text }
+    }
+
+    /// The source code attached to a diagnostic, miette works out the line and
+    /// column of its spans from the byte offsets.
+    pub fn named(&self) -> NamedSource<String> {
+        NamedSource::new(self.name, self.text.to_string())
+    }
+}
+
+impl<'a> From<&'a str> for Source<'a> {
+    fn from(text: &'a str) -> Self {
+        Self::new("<input>", text)
+    }
+}
+
+/// An owned copy of a source, kept by the functions defined in it so their
+/// diagnostics still point into it after the evaluation that made them.
+#[derive(Debug)]
+pub struct OwnedSource {
+    pub name: String,
+    pub text: String,
+}
+
+impl OwnedSource {
+    pub fn source(&self) -> Source<'_> {
+        Source::new(&self.name, &self.text)
+    }
+}
+
+impl From<Source<'_>> for OwnedSource {
+    fn from(source: Source) -> Self {
+        Self {
+            name: source.name.to_string(),
+            text: source.text.to_string(),
+        }
+    }
+}
@@ -1,9 +1,28 @@
-use miette::{Diagnostic, Result};
+use crate::function::Function;
+use crate::number::Number;
+use crate::units::Unit;
+use miette::{Diagnostic, Result, SourceSpan};
+use std::rc::Rc;
 use thiserror::Error;
-pub type Value = (u8, f64);
+
+/// A value on the VM stack: a number in some unit, a string, a list of values or
+/// a function.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Value {
+    Number(Number, Unit),
+    Str(String),
+    List(Vec<Value>),
+    Function(Rc<Function>),
+}
+
+impl From<f64> for Value {
+    fn from(num: f64) -> Self {
+        Value::Number(num.into(), Unit::default())
+    }
+}
 
 pub struct Stack {
-    items: [Value; 1024],
+    items: [(SourceSpan, Value); 1024],
     stack_top: u16,
 }
 
@@ -24,14 +43,14 @@ struct StackOverflow {}
 struct StackUnderflow {}
 
 impl Stack {
-    pub const fn new() -> Self {
+    pub fn new() -> Self {
         Self {
-            items: [(0, 0.0); 1024],
+            items: std::array::from_fn(|_| (0.into(), 0.0.into())),
             stack_top: 0,
         }
     }
 
-    pub fn push(&mut self, value: Value) -> Result<()> {
+    pub fn push(&mut self, value: (SourceSpan, Value)) -> Result<()> {
         if self.stack_top as usize >= self.items.len() {
             return Err(StackOverflow {})?;
         }
@@ -40,12 +59,29 @@ impl Stack {
         Ok(())
     }
 
-    pub fn pop(&mut self) -> Result<Value> {
+    pub fn len(&self) -> usize {
+        self.stack_top as usize
+    }
+
+    /// Drops everything above the first `len` values, what a call that failed
+    /// part way through left behind.
+    pub fn truncate(&mut self, len: usize) {
+        while self.len() > len {
+            self.stack_top -= 1;
+            self.items[self.stack_top as usize] = (0.into(), 0.0.into());
+        }
+    }
+
+    pub fn pop(&mut self) -> Result<(SourceSpan, Value)> {
         if self.stack_top == 0 {
             return Err(StackUnderflow {})?;
         }
         self.stack_top -= 1;
-        Ok(self.items[self.stack_top as usize])
+        let empty = (0.into(), 0.0.into());
+        Ok(std::mem::replace(
+            &mut self.items[self.stack_top as usize],
+            empty,
+        ))
     }
 }
 
@@ -56,10 +92,13 @@ mod test {
     #[test]
     fn stack_operations() {
         let mut stack = Stack::new();
-        stack.push((0, 1.)).unwrap();
-        stack.push((0, 2.)).unwrap();
-        stack.push((0, 3.)).unwrap();
+        stack.push((0.into(), 1.0.into())).unwrap();
+        stack.push((0.into(), 2.0.into())).unwrap();
+        stack.push((0.into(), 3.0.into())).unwrap();
         let _ = stack.pop().unwrap();
         assert_eq!(stack.stack_top, 2);
+        stack.truncate(1);
+        assert_eq!(stack.len(), 1);
+        assert_eq!(stack.pop().unwrap().1, 1.0.into());
     }
 }
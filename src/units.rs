@@ -0,0 +1,254 @@
+use std::fmt;
+
+// Exponents of the SI base dimensions: length, mass, time, current, temperature,
+// amount of substance and luminous intensity, wider than the exponents of units
+// so `L^50` still fits
+pub type Dimension = [i32; 7];
+
+const NONE: Dimension = [0, 0, 0, 0, 0, 0, 0];
+const LENGTH: Dimension = [1, 0, 0, 0, 0, 0, 0];
+const MASS: Dimension = [0, 1, 0, 0, 0, 0, 0];
+const TIME: Dimension = [0, 0, 1, 0, 0, 0, 0];
+const CURRENT: Dimension = [0, 0, 0, 1, 0, 0, 0];
+const TEMPERATURE: Dimension = [0, 0, 0, 0, 1, 0, 0];
+const AMOUNT: Dimension = [0, 0, 0, 0, 0, 1, 0];
+const LUMINOSITY: Dimension = [0, 0, 0, 0, 0, 0, 1];
+const AREA: Dimension = [2, 0, 0, 0, 0, 0, 0];
+const VOLUME: Dimension = [3, 0, 0, 0, 0, 0, 0];
+const SPEED: Dimension = [1, 0, -1, 0, 0, 0, 0];
+const FREQUENCY: Dimension = [0, 0, -1, 0, 0, 0, 0];
+const FORCE: Dimension = [1, 1, -2, 0, 0, 0, 0];
+const PRESSURE: Dimension = [-1, 1, -2, 0, 0, 0, 0];
+const ENERGY: Dimension = [2, 1, -2, 0, 0, 0, 0];
+const POWER: Dimension = [2, 1, -3, 0, 0, 0, 0];
+const CHARGE: Dimension = [0, 0, 1, 1, 0, 0, 0];
+const VOLTAGE: Dimension = [2, 1, -3, -1, 0, 0, 0];
+const RESISTANCE: Dimension = [2, 1, -3, -2, 0, 0, 0];
+
+// name, size in SI base units, dimension
+const UNITS: &[(&str, f64, Dimension)] = &[
+    ("m", 1.0, LENGTH),
+    ("km", 1e3, LENGTH),
+    ("cm", 1e-2, LENGTH),
+    ("mm", 1e-3, LENGTH),
+    ("um", 1e-6, LENGTH),
+    ("nm", 1e-9, LENGTH),
+    ("inch", 0.0254, LENGTH),
+    ("ft", 0.3048, LENGTH),
+    ("yd", 0.9144, LENGTH),
+    ("mi", 1609.344, LENGTH),
+    ("nmi", 1852.0, LENGTH),
+    ("g", 1e-3, MASS),
+    ("kg", 1.0, MASS),
+    ("mg", 1e-6, MASS),
+    ("tonne", 1e3, MASS),
+    ("lb", 0.45359237, MASS),
+    ("oz", 0.028349523125, MASS),
+    ("s", 1.0, TIME),
+    ("ms", 1e-3, TIME),
+    ("us", 1e-6, TIME),
+    ("ns", 1e-9, TIME),
+    ("min", 60.0, TIME),
+    ("h", 3600.0, TIME),
+    ("day", 86400.0, TIME),
+    ("week", 604800.0, TIME),
+    ("yr", 31557600.0, TIME),
+    ("A", 1.0, CURRENT),
+    ("mA", 1e-3, CURRENT),
+    ("K", 1.0, TEMPERATURE),
+    ("mol", 1.0, AMOUNT),
+    ("cd", 1.0, LUMINOSITY),
+    ("ha", 1e4, AREA),
+    ("acre", 4046.8564224, AREA),
+    ("L", 1e-3, VOLUME),
+    ("mL", 1e-6, VOLUME),
+    ("gal", 3.785411784e-3, VOLUME),
+    ("mph", 0.44704, SPEED),
+    ("kph", 1.0 / 3.6, SPEED),
+    ("knot", 1852.0 / 3600.0, SPEED),
+    ("Hz", 1.0, FREQUENCY),
+    ("kHz", 1e3, FREQUENCY),
+    ("MHz", 1e6, FREQUENCY),
+    ("GHz", 1e9, FREQUENCY),
+    ("N", 1.0, FORCE),
+    ("kN", 1e3, FORCE),
+    ("lbf", 4.4482216152605, FORCE),
+    ("Pa", 1.0, PRESSURE),
+    ("kPa", 1e3, PRESSURE),
+    ("bar", 1e5, PRESSURE),
+    ("atm", 101325.0, PRESSURE),
+    ("psi", 6894.757293168, PRESSURE),
+    ("J", 1.0, ENERGY),
+    ("kJ", 1e3, ENERGY),
+    ("cal", 4.184, ENERGY),
+    ("kcal", 4184.0, ENERGY),
+    ("Wh", 3600.0, ENERGY),
+    ("kWh", 3.6e6, ENERGY),
+    ("eV", 1.602176634e-19, ENERGY),
+    ("W", 1.0, POWER),
+    ("kW", 1e3, POWER),
+    ("MW", 1e6, POWER),
+    ("hp", 745.69987158227, POWER),
+    ("C", 1.0, CHARGE),
+    ("V", 1.0, VOLTAGE),
+    ("mV", 1e-3, VOLTAGE),
+    ("kV", 1e3, VOLTAGE),
+    ("ohm", 1.0, RESISTANCE),
+];
+
+/// A product of registered units raised to integer powers, e.g. `kg*m/s^2`.
+/// Plain numbers carry the empty unit.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Unit {
+    terms: Vec<(&'static str, i8)>,
+}
+
+pub fn lookup(name: &str) -> Option<Unit> {
+    UNITS
+        .iter()
+        .find(|(unit, _, _)| *unit == name)
+        .map(|(unit, _, _)| Unit {
+            terms: vec![(unit, 1)],
+        })
+}
+
+fn entry(name: &str) -> (f64, Dimension) {
+    let (_, factor, dim) = UNITS.iter().find(|(unit, _, _)| *unit == name).unwrap();
+    (*factor, *dim)
+}
+
+impl Unit {
+    pub fn is_empty(&self) -> bool {
+        self.terms.is_empty()
+    }
+
+    /// Size of one of this unit in SI base units.
+    pub fn factor(&self) -> f64 {
+        self.terms
+            .iter()
+            .map(|(name, exp)| entry(name).0.powi(*exp as i32))
+            .product()
+    }
+
+    pub fn dimension(&self) -> Dimension {
+        let mut dim = NONE;
+        for (name, exp) in &self.terms {
+            for (total, base) in dim.iter_mut().zip(entry(name).1) {
+                *total += base * *exp as i32;
+            }
+        }
+        dim
+    }
+
+    /// `None` when an exponent leaves the range of `i8`, as do the ones below.
+    pub fn powi(&self, exp: i8) -> Option<Unit> {
+        let terms = self
+            .terms
+            .iter()
+            .map(|(name, e)| Some((*name, e.checked_mul(exp)?)))
+            .collect::<Option<_>>()?;
+        Some(Unit { terms })
+    }
+
+    pub fn mul(&self, other: &Unit) -> Option<Unit> {
+        let mut terms = self.terms.clone();
+        for (name, exp) in &other.terms {
+            match terms.iter_mut().find(|(n, _)| n == name) {
+                Some((_, e)) => *e = e.checked_add(*exp)?,
+                None => terms.push((name, *exp)),
+            }
+        }
+        terms.retain(|(_, exp)| *exp != 0);
+        Some(Unit { terms })
+    }
+
+    pub fn div(&self, other: &Unit) -> Option<Unit> {
+        self.mul(&other.powi(-1)?)
+    }
+}
+
+/// Folds units that cancel out (`km/m`) back into a plain number.
+pub fn simplify(value: f64, unit: Unit) -> (f64, Unit) {
+    if unit.dimension() == NONE {
+        (value * unit.factor(), Unit::default())
+    } else {
+        (value, unit)
+    }
+}
+
+fn write_term(f: &mut fmt::Formatter<'_>, name: &str, exp: i32) -> fmt::Result {
+    if exp == 1 {
+        write!(f, "{}", name)
+    } else {
+        write!(f, "{}^{}", name, exp)
+    }
+}
+
+impl fmt::Display for Unit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let numerator: Vec<_> = self.terms.iter().filter(|(_, e)| *e > 0).collect();
+        let denominator: Vec<_> = self.terms.iter().filter(|(_, e)| *e < 0).collect();
+        if numerator.is_empty() {
+            write!(f, "1")?;
+        }
+        for (i, (name, exp)) in numerator.iter().enumerate() {
+            if i > 0 {
+                write!(f, "*")?;
+            }
+            write_term(f, name, *exp as i32)?;
+        }
+        if denominator.is_empty() {
+            return Ok(());
+        }
+        write!(f, "/")?;
+        if denominator.len() > 1 {
+            write!(f, "(")?;
+        }
+        for (i, (name, exp)) in denominator.iter().enumerate() {
+            if i > 0 {
+                write!(f, "*")?;
+            }
+            write_term(f, name, -(*exp as i32))?;
+        }
+        if denominator.len() > 1 {
+            write!(f, ")")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn unit_algebra() {
+        let kg = lookup("kg").unwrap();
+        let m = lookup("m").unwrap();
+        let s = lookup("s").unwrap();
+        let accel = m.div(&s.powi(2).unwrap()).unwrap();
+        assert_eq!(accel.to_string(), "m/s^2");
+        let force = kg.mul(&accel).unwrap();
+        assert_eq!(force.to_string(), "kg*m/s^2");
+        assert_eq!(force.dimension(), lookup("N").unwrap().dimension());
+        assert_eq!(lookup("Hz").unwrap().to_string(), "Hz");
+        assert_eq!(m.div(&kg.mul(&s).unwrap()).unwrap().to_string(), "m/(kg*s)");
+        let big = m.powi(100).unwrap();
+        assert!(big.mul(&big).is_none());
+        assert!(m.powi(-128).unwrap().div(&m).is_none());
+        assert_eq!(m.powi(-128).unwrap().to_string(), "1/m^128");
+        assert_eq!(lookup("L").unwrap().powi(50).unwrap().dimension()[0], 150);
+    }
+
+    #[test]
+    fn unit_factors() {
+        let km = lookup("km").unwrap();
+        let h = lookup("h").unwrap();
+        assert_eq!(km.div(&h).unwrap().factor(), 1000.0 / 3600.0);
+        assert_eq!(
+            simplify(3.0, km.div(&lookup("m").unwrap()).unwrap()),
+            (3000.0, Unit::default())
+        );
+        assert!(lookup("furlong").is_none());
+    }
+}
@@ -1,102 +1,1938 @@
-use crate::stack::Stack;
-use miette::{Diagnostic, Result, SourceSpan};
-use std::fmt::Write;
+use crate::compiler;
+use crate::format::{self, Base, Style};
+use crate::function::{Function, Lambda};
+use crate::matrix::{self, Matrix};
+use crate::natives::{self, Angle, AngleMode, Arity, Builtin, ComplexFn, Func, ModMode, Native};
+use crate::number::{self, Number};
+use crate::numeric;
+use crate::source::{OwnedSource, Source};
+use crate::stack::{Stack, Value};
+use crate::units::{self, Unit};
+use miette::{Diagnostic, NamedSource, Report, Result, SourceSpan};
+use num_bigint::BigInt;
+use num_complex::Complex64;
+use num_traits::{FromPrimitive, ToPrimitive};
+use std::collections::HashMap;
+use std::fmt::{self, Write};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::str::FromStr;
 use thiserror::Error;
 
 pub enum Opcode {
     Add,
     Sub,
     Div,
+    IntDiv,
     Mult,
+    MatMul,
     Nop,
     Mod,
     Neg,
-    Num(u8, f64),
+    BitAnd,
+    BitOr,
+    Xor,
+    Shl,
+    Shr,
+    BitNot,
+    Pow,
+    Percent,
+    PercentAdd,
+    PercentSub,
+    Num(SourceSpan, f64),
+    Int(SourceSpan, BigInt),
+    Overflowed(SourceSpan),
+    Imaginary(SourceSpan, f64),
+    Str(SourceSpan, String),
+    Quantity(SourceSpan, f64, Unit),
+    Convert(SourceSpan, Unit),
+    GetVar(SourceSpan, String),
+    SetVar(String),
+    Import(SourceSpan, String),
+    Call(SourceSpan, &'static Native, usize),
+    List(SourceSpan, usize),
+    Index(SourceSpan),
+    Fact(SourceSpan),
+    Range(bool),
+    Iter,
+    /// Binds the next item of the loop to a variable, or jumps past the loop
+    Next(String, usize),
+    Collect,
+    /// Jumps back to the head of the loop
+    Loop(usize),
+    Closure(Rc<Lambda>),
+    /// Calls the function below its arguments on the stack
+    Apply(SourceSpan, usize),
+    Pop,
+    Print,
     Ret,
 }
 
 pub type Chunk = Vec<Opcode>;
 
+/// Variables assigned with `$name = value`, kept between evaluations by the caller.
+pub type Globals = HashMap<String, Value>;
+
+/// Which results of a sequence of statements are printed.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Echo {
+    /// Only the result of the last statement.
+    #[default]
+    Last,
+    /// Every result except those of assignments before the last statement.
+    All,
+}
+
+impl FromStr for Echo {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "last" => Ok(Echo::Last),
+            "all" => Ok(Echo::All),
+            _ => Err(format!("Unknown echo mode {s}, expected one of last|all")),
+        }
+    }
+}
+
+impl fmt::Display for Echo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Echo::Last => write!(f, "last"),
+            Echo::All => write!(f, "all"),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Default)]
+pub struct Settings {
+    pub base: Base,
+    pub angle: AngleMode,
+    pub modulo: ModMode,
+    /// Let NaN and infinity through like plain IEEE floats instead of reporting them.
+    pub ieee: bool,
+    /// Let real functions such as `sqrt` and `ln` return complex results outside
+    /// their real domain instead of reporting a domain error.
+    pub complex: bool,
+    /// Decimals, notation and separators of printed numbers.
+    pub style: Style,
+    pub echo: Echo,
+}
+
 pub struct Vm<'a> {
-    chunk: Chunk,
+    chunk: Rc<Chunk>,
     stack: Stack,
     ip: usize,
-    src: &'a str,
+    src: Source<'a>,
+    settings: Settings,
+    globals: Globals,
+    // Scripts being run, each one imported by the one before it
+    imports: Vec<PathBuf>,
+    // Instructions run so far and the most this evaluation may run
+    steps: usize,
+    budget: usize,
+    // Function calls this evaluation runs inside of
+    depth: usize,
+    // The source shared by the functions defined in it
+    shared: Option<Rc<OwnedSource>>,
 }
 
+/// Instructions a single evaluation may run before it is stopped.
+pub const DEFAULT_BUDGET: usize = 10_000_000;
+
+/// Most function calls that may run inside each other.
+pub const MAX_DEPTH: usize = 32;
+
+/// Most numbers a range may hold.
+pub const MAX_RANGE: usize = 1_000_000;
+
 #[derive(Error, Debug, Diagnostic)]
 #[error("No return opcode emitted!")]
 struct NoReturnOpcode {}
 
+#[derive(Error, Debug, Diagnostic)]
+#[error("Undefined variable!")]
+#[diagnostic(help("assign it first with $name = value"))]
+struct UndefinedVariable {
+    #[source_code]
+    src: NamedSource<String>,
+    #[label("This variable has not been set")]
+    bad_bit: SourceSpan,
+}
+
 #[derive(Error, Debug, Diagnostic)]
 #[error("Division by zero!")]
 #[diagnostic(help("try to divide by anything other than that"))]
 struct DivByZero {
     #[source_code]
-    src: String,
+    src: NamedSource<String>,
+    #[label("This part here")]
+    bad_bit: SourceSpan,
+}
+
+#[derive(Error, Debug, Diagnostic)]
+#[error("Math domain error!")]
+#[diagnostic(help(
+    "check the arguments, or turn on :complex for complex results or :ieee to get NaN"
+))]
+struct DomainError {
+    #[source_code]
+    src: NamedSource<String>,
+    #[label("{name} is undefined here")]
+    bad_bit: SourceSpan,
+    name: &'static str,
+}
+
+#[derive(Error, Debug, Diagnostic)]
+#[error("Result is infinite!")]
+#[diagnostic(help("the result is too large to represent, turn on :ieee to get inf instead"))]
+struct Overflow {
+    #[source_code]
+    src: NamedSource<String>,
     #[label("This part here")]
     bad_bit: SourceSpan,
 }
 
+#[derive(Error, Debug, Diagnostic)]
+#[error("Expected a real number!")]
+#[diagnostic(help("take re(z), im(z) or abs(z) of a complex number first"))]
+struct NotReal {
+    #[source_code]
+    src: NamedSource<String>,
+    #[label("This is complex")]
+    bad_bit: SourceSpan,
+}
+
+#[derive(Error, Debug, Diagnostic)]
+#[error("Bitwise operators need whole numbers!")]
+#[diagnostic(help("try rounding the operand first"))]
+struct NonIntegralOperand {
+    #[source_code]
+    src: NamedSource<String>,
+    #[label("This is not a whole number")]
+    bad_bit: SourceSpan,
+}
+
+#[derive(Error, Debug, Diagnostic)]
+#[error("Expected a plain number!")]
+#[diagnostic(help("try converting the operand or dividing its unit out"))]
+struct UnitNotAllowed {
+    #[source_code]
+    src: NamedSource<String>,
+    #[label("This is in {unit}")]
+    bad_bit: SourceSpan,
+    unit: Unit,
+}
+
+#[derive(Error, Debug, Diagnostic)]
+#[error("Factorial needs a non-negative whole number!")]
+#[diagnostic(help("use gamma(x + 1) for fractional arguments"))]
+struct InvalidFactorial {
+    #[source_code]
+    src: NamedSource<String>,
+    #[label("This is not a non-negative whole number")]
+    bad_bit: SourceSpan,
+}
+
+#[derive(Error, Debug, Diagnostic)]
+#[error("Shift amount out of range!")]
+#[diagnostic(help("shift by anything from 0 to 63"))]
+struct ShiftOutOfRange {
+    #[source_code]
+    src: NamedSource<String>,
+    #[label("This shift amount")]
+    bad_bit: SourceSpan,
+}
+
+#[derive(Error, Debug, Diagnostic)]
+#[error("Unit power out of range!")]
+#[diagnostic(help("the power of each unit must stay between -128 and 127"))]
+struct UnitPowerRange {
+    #[source_code]
+    src: NamedSource<String>,
+    #[label("This is {left_unit}")]
+    left: SourceSpan,
+    #[label("This is {right_unit}")]
+    right: SourceSpan,
+    left_unit: String,
+    right_unit: String,
+}
+
+#[derive(Error, Debug, Diagnostic)]
+#[error("Mismatched dimensions!")]
+#[diagnostic(help("both sides need units measuring the same kind of quantity"))]
+struct DimensionMismatch {
+    #[source_code]
+    src: NamedSource<String>,
+    #[label("This is {left_unit}")]
+    left: SourceSpan,
+    #[label("This is {right_unit}")]
+    right: SourceSpan,
+    left_unit: String,
+    right_unit: String,
+}
+
+#[derive(Error, Debug, Diagnostic)]
+#[error("Mismatched types!")]
+#[diagnostic(help("strings only add to other strings, use concat or format to mix in numbers"))]
+struct TypeMismatch {
+    #[source_code]
+    src: NamedSource<String>,
+    #[label("This is {left_type}")]
+    left: SourceSpan,
+    #[label("This is {right_type}")]
+    right: SourceSpan,
+    left_type: &'static str,
+    right_type: &'static str,
+}
+
+#[derive(Error, Debug, Diagnostic)]
+#[error("Expected a number!")]
+#[diagnostic(help("strings can only be joined with +, concat or format"))]
+struct ExpectedNumber {
+    #[source_code]
+    src: NamedSource<String>,
+    #[label("This is {kind}")]
+    bad_bit: SourceSpan,
+    kind: &'static str,
+}
+
+#[derive(Error, Debug, Diagnostic)]
+#[error("Range is too long!")]
+#[diagnostic(help("ranges hold at most {max} numbers"))]
+struct RangeTooLong {
+    #[source_code]
+    src: NamedSource<String>,
+    #[label("This range here")]
+    bad_bit: SourceSpan,
+    max: usize,
+}
+
+#[derive(Error, Debug, Diagnostic)]
+#[error("Expected a function!")]
+#[diagnostic(help("functions are written like x -> $x * 2 or (a, b) -> $a + $b"))]
+struct NotAFunction {
+    #[source_code]
+    src: NamedSource<String>,
+    #[label("This is {kind}")]
+    bad_bit: SourceSpan,
+    kind: &'static str,
+}
+
+#[derive(Error, Debug, Diagnostic)]
+#[error("Function expects {arity} but got {got}!")]
+#[diagnostic(help("the function is {function}"))]
+struct FunctionArity {
+    #[source_code]
+    src: NamedSource<String>,
+    #[label("This call here")]
+    bad_bit: SourceSpan,
+    arity: Arity,
+    got: usize,
+    function: String,
+}
+
+#[derive(Error, Debug, Diagnostic)]
+#[error("Error in function {function}!")]
+struct CallError {
+    #[source_code]
+    src: NamedSource<String>,
+    #[label("Called here")]
+    bad_bit: SourceSpan,
+    function: String,
+    #[related]
+    errors: Vec<Report>,
+}
+
+#[derive(Error, Debug, Diagnostic)]
+#[error("{name} did not converge!")]
+#[diagnostic(help("{reason}"))]
+struct NoConvergence {
+    #[source_code]
+    src: NamedSource<String>,
+    #[label("This call here")]
+    bad_bit: SourceSpan,
+    name: &'static str,
+    reason: &'static str,
+}
+
+#[derive(Error, Debug, Diagnostic)]
+#[error("Too many nested calls!")]
+#[diagnostic(help("functions may only call each other {max} deep"))]
+struct TooDeep {
+    #[source_code]
+    src: NamedSource<String>,
+    #[label("This call here")]
+    bad_bit: SourceSpan,
+    max: usize,
+}
+
+#[derive(Error, Debug, Diagnostic)]
+#[error("Instruction budget exhausted!")]
+#[diagnostic(help(
+    "the calculation ran for more than {budget} steps, check loops for huge ranges"
+))]
+struct BudgetExhausted {
+    budget: usize,
+}
+
+#[derive(Error, Debug, Diagnostic)]
+#[error("Invalid format string!")]
+#[diagnostic(help("{reason}"))]
+struct FormatError {
+    #[source_code]
+    src: NamedSource<String>,
+    #[label("This format string")]
+    bad_bit: SourceSpan,
+    reason: String,
+}
+
+#[derive(Error, Debug, Diagnostic)]
+#[error("Could not import {path}!")]
+#[diagnostic(help("{reason}"))]
+struct ImportFailed {
+    #[source_code]
+    src: NamedSource<String>,
+    #[label("This import")]
+    bad_bit: SourceSpan,
+    path: String,
+    reason: String,
+}
+
+#[derive(Error, Debug, Diagnostic)]
+#[error("Import cycle!")]
+#[diagnostic(help("{chain} imports itself again"))]
+struct ImportCycle {
+    #[source_code]
+    src: NamedSource<String>,
+    #[label("This import")]
+    bad_bit: SourceSpan,
+    chain: String,
+}
+
+#[derive(Error, Debug, Diagnostic)]
+#[error("Error in imported file {path}!")]
+struct ImportError {
+    #[source_code]
+    src: NamedSource<String>,
+    #[label("Imported here")]
+    bad_bit: SourceSpan,
+    path: String,
+    #[related]
+    errors: Vec<Report>,
+}
+
+#[derive(Error, Debug, Diagnostic)]
+#[error("Mismatched list lengths!")]
+#[diagnostic(help("element-wise operations need lists of the same length"))]
+struct LengthMismatch {
+    #[source_code]
+    src: NamedSource<String>,
+    #[label("This has {left_len} elements")]
+    left: SourceSpan,
+    #[label("This has {right_len} elements")]
+    right: SourceSpan,
+    left_len: usize,
+    right_len: usize,
+}
+
+#[derive(Error, Debug, Diagnostic)]
+#[error("Expected a list!")]
+#[diagnostic(help("lists are written in brackets, like [1, 2, 3]"))]
+struct NotAList {
+    #[source_code]
+    src: NamedSource<String>,
+    #[label("This is not a list")]
+    bad_bit: SourceSpan,
+}
+
+#[derive(Error, Debug, Diagnostic)]
+#[error("Index out of range!")]
+#[diagnostic(help("indexes are whole numbers below {len}, negative ones count from the end"))]
+struct IndexOutOfRange {
+    #[source_code]
+    src: NamedSource<String>,
+    #[label("This index here")]
+    bad_bit: SourceSpan,
+    len: usize,
+}
+
+#[derive(Error, Debug, Diagnostic)]
+#[error("Expected a matrix!")]
+#[diagnostic(help("matrices are lists of rows of the same length, like [[1, 2], [3, 4]]"))]
+struct NotAMatrix {
+    #[source_code]
+    src: NamedSource<String>,
+    #[label("This is not a matrix")]
+    bad_bit: SourceSpan,
+}
+
+#[derive(Error, Debug, Diagnostic)]
+#[error("Mismatched matrix dimensions!")]
+#[diagnostic(help("the columns of the left operand must match the rows of the right one"))]
+struct ShapeMismatch {
+    #[source_code]
+    src: NamedSource<String>,
+    #[label("This is {left_shape}")]
+    left: SourceSpan,
+    #[label("This is {right_shape}")]
+    right: SourceSpan,
+    left_shape: String,
+    right_shape: String,
+}
+
+#[derive(Error, Debug, Diagnostic)]
+#[error("Expected a square matrix!")]
+#[diagnostic(help("only square matrices have a determinant or an inverse"))]
+struct NotSquare {
+    #[source_code]
+    src: NamedSource<String>,
+    #[label("This is {shape}")]
+    bad_bit: SourceSpan,
+    shape: String,
+}
+
+#[derive(Error, Debug, Diagnostic)]
+#[error("Matrix is singular!")]
+#[diagnostic(help("its determinant is zero, so it has no inverse"))]
+struct SingularMatrix {
+    #[source_code]
+    src: NamedSource<String>,
+    #[label("This matrix")]
+    bad_bit: SourceSpan,
+}
+
+#[derive(Error, Debug, Diagnostic)]
+#[error("{name} needs at least one value!")]
+#[diagnostic(help("the lists passed to it are empty"))]
+struct EmptyList {
+    #[source_code]
+    src: NamedSource<String>,
+    #[label("No values given here")]
+    bad_bit: SourceSpan,
+    name: &'static str,
+}
+
+fn join(a: SourceSpan, b: SourceSpan) -> SourceSpan {
+    let start = a.offset().min(b.offset());
+    let end = (a.offset() + a.len()).max(b.offset() + b.len());
+    (start, end - start).into()
+}
+
+fn describe_shape(matrix: &Matrix) -> String {
+    let (rows, cols) = matrix::shape(matrix);
+    format!("{}x{}", rows, cols)
+}
+
+// A flat list of numbers, which `@` treats as a row or a column vector
+fn is_vector(value: &Value) -> bool {
+    matches!(value, Value::List(items) if items.iter().all(|item| matches!(item, Value::Number(..))))
+}
+
+fn from_vector(vector: Vec<f64>) -> Value {
+    Value::List(vector.into_iter().map(Value::from).collect())
+}
+
+fn from_matrix(matrix: Matrix) -> Value {
+    Value::List(matrix.into_iter().map(from_vector).collect())
+}
+
+fn type_name(value: &Value) -> &'static str {
+    match value {
+        Value::Number(..) => "a number",
+        Value::Str(_) => "a string",
+        Value::List(_) => "a list",
+        Value::Function(_) => "a function",
+    }
+}
+
+fn describe(unit: &Unit) -> String {
+    if unit.is_empty() {
+        "a plain number".to_string()
+    } else {
+        format!("in {}", unit)
+    }
+}
+
+// Big integers stay exact through `+`, `-` and `*` of plain numbers
+fn exact(op: &str, a: &Number, unit_a: &Unit, b: &Number, unit_b: &Unit) -> Option<Number> {
+    if !unit_a.is_empty() || !unit_b.is_empty() {
+        return None;
+    }
+    let op: fn(BigInt, BigInt) -> BigInt = match op {
+        "+" => |a, b| a + b,
+        "-" => |a, b| a - b,
+        "*" => |a, b| a * b,
+        _ => return None,
+    };
+    a.exact_op(b, op)
+}
+
+type MathFn = fn(&[f64]) -> Number;
+
+// An operation on one number and its unit, applied element-wise to lists
+type UnaryFn<'f> = dyn Fn(Number, Unit) -> Result<(Number, Unit)> + 'f;
+type BinaryFn<'f> = dyn Fn(Number, Unit, Number, Unit) -> Result<(Number, Unit)> + 'f;
+
+// `+`, `-` and `%` convert the right operand into the unit of the left one, `+`
+// also joins two strings
 macro_rules! binary_op {
-    ($self: expr, $op:tt) => {{
-        let (offset_b, b) = $self.stack.pop()?;
-        let (offset_a, a)= $self.stack.pop()?;
-        if stringify!($op) == "/" || stringify!($op) == "%" {
-            if b == 0. {
-                let span = offset_b - offset_a;
-                let a_len = (a.log(10.0) + 1.0).abs() as u8;
+    ($self: expr, $op:tt) => {
+        binary_op!($self, $op, |a: f64, b: f64| a $op b)
+    };
+    ($self: expr, $op:tt, $apply:expr) => {{
+        let (span_b, b) = $self.stack.pop()?;
+        let (span_a, a) = $self.stack.pop()?;
+        let span = join(span_a, span_b);
+        let value = match (a, b) {
+            (Value::Str(a), Value::Str(b)) if stringify!($op) == "+" => Value::Str(a + &b),
+            (a, b) => $self.broadcast(span_a, a, span_b, b, &|a, unit_a, b, unit_b| {
+                if let Some(value) = exact(stringify!($op), &a, &unit_a, &b, &unit_b) {
+                    return Ok((value, unit_a));
+                }
+                if a.is_complex() || b.is_complex() {
+                    return $self.complex_op(stringify!($op), (span_a, a, unit_a), (span_b, b, unit_b));
+                }
+                let a = a.to_f64();
+                let b = $self.align(span_a, &unit_a, span_b, b.to_f64(), &unit_b)?;
+                if stringify!($op) == "%" && b == 0. && !$self.settings.ieee {
+                    return Err(DivByZero {
+                        src: $self.source().named(),
+                        bad_bit: span,
+                    })?
+                }
+                Ok(($self.finite(span, $apply(a, b), &[a, b])?.into(), unit_a))
+            })?,
+        };
+        $self.stack.push((span, value))?;
+    }};
+}
+
+// `*` and `/` combine the units of both operands, e.g. `m / s` gives `m/s`
+macro_rules! scaling_op {
+    ($self: expr, $op:tt, $combine:ident) => {
+        scaling_op!($self, $op, $combine, |x: f64| x, stringify!($op))
+    };
+    ($self: expr, $op:tt, $combine:ident, $round:expr, $name:expr) => {{
+        let (span_b, b) = $self.stack.pop()?;
+        let (span_a, a) = $self.stack.pop()?;
+        let span = join(span_a, span_b);
+        let value = $self.broadcast(span_a, a, span_b, b, &|a, unit_a, b, unit_b| {
+            if let Some(value) = exact($name, &a, &unit_a, &b, &unit_b) {
+                return Ok((value, unit_a));
+            }
+            if a.is_complex() || b.is_complex() {
+                return $self.complex_op($name, (span_a, a, unit_a), (span_b, b, unit_b));
+            }
+            let (a, b) = (a.to_f64(), b.to_f64());
+            if $name != "*" && b == 0. && !$self.settings.ieee {
                 return Err(DivByZero {
-                    src: $self.src.to_string(),
-                    bad_bit: ((offset_a - a_len) as usize, (span + a_len) as usize).into()
+                    src: $self.source().named(),
+                    bad_bit: span,
                 })?
             }
-        }
-        $self.stack.push((0, a $op b))?;
+            let unit = $self.combine(span_a, &unit_a, span_b, &unit_b, unit_a.$combine(&unit_b))?;
+            let (value, unit) = units::simplify(a $op b, unit);
+            Ok(($self.finite(span, $round(value), &[a, b])?.into(), unit))
+        })?;
+        $self.stack.push((span, value))?;
+    }};
+}
+
+// `a + p%` and `a - p%` grow or shrink `a` by `p` percent of itself
+macro_rules! percent_op {
+    ($self: expr, $op:tt) => {{
+        let (span_p, p) = $self.stack.pop()?;
+        let (span_a, a) = $self.stack.pop()?;
+        let span = join(span_a, span_p);
+        let value = $self.broadcast(span_a, a, span_p, p, &|a, unit_a, p, unit_p| {
+            $self.plain(span_p, &unit_p)?;
+            let (a, p) = ($self.real(span_a, a)?, $self.real(span_p, p)?);
+            Ok(($self.finite(span, a $op a * p, &[a, p])?.into(), unit_a))
+        })?;
+        $self.stack.push((span, value))?;
+    }};
+}
+
+macro_rules! bitwise_op {
+    ($self: expr, $op:tt) => {{
+        let (span_b, b) = $self.stack.pop()?;
+        let (span_a, a) = $self.stack.pop()?;
+        let value = $self.broadcast(span_a, a, span_b, b, &|a, unit_a, b, unit_b| {
+            let a = $self.integral(span_a, a, unit_a)?;
+            let b = $self.integral(span_b, b, unit_b)?;
+            Ok((Number::Int(a $op b), Unit::default()))
+        })?;
+        $self.stack.push((join(span_a, span_b), value))?;
+    }};
+}
+
+macro_rules! shift_op {
+    ($self: expr, $op:tt) => {{
+        let (span_b, b) = $self.stack.pop()?;
+        let (span_a, a) = $self.stack.pop()?;
+        let value = $self.broadcast(span_a, a, span_b, b, &|a, unit_a, b, unit_b| {
+            let a = $self.integral(span_a, a, unit_a)?;
+            let b = $self.integral(span_b, b, unit_b)?;
+            let b = b.to_u32().filter(|b| *b < 64).ok_or(ShiftOutOfRange {
+                src: $self.source().named(),
+                bad_bit: span_b,
+            })?;
+            Ok((Number::Int(a $op b), Unit::default()))
+        })?;
+        $self.stack.push((join(span_a, span_b), value))?;
     }};
 }
 
 impl<'a> Vm<'a> {
-    pub fn new(source: &'a str, chunk: Chunk) -> Self {
+    pub fn new(source: &'a str, chunk: impl Into<Rc<Chunk>>) -> Self {
+        let chunk = chunk.into();
         assert!(!chunk.is_empty());
         Self {
             chunk,
             stack: Stack::new(),
             ip: 0,
-            src: source,
+            src: source.into(),
+            settings: Settings::default(),
+            globals: Globals::new(),
+            imports: Vec::new(),
+            steps: 0,
+            budget: DEFAULT_BUDGET,
+            depth: 0,
+            shared: None,
         }
     }
+
+    /// Limits the instructions an evaluation may run, stopping runaway loops.
+    pub fn with_budget(mut self, budget: usize) -> Self {
+        self.budget = budget;
+        self
+    }
+
+    /// Names the source in diagnostics, a script path rather than `<input>`.
+    pub fn with_name(mut self, name: &'a str) -> Self {
+        self.src.name = name;
+        self
+    }
+
+    /// Runs the chunk as the script at `path`, its imports are relative to it.
+    pub fn with_file(mut self, path: PathBuf) -> Self {
+        self.imports = vec![path];
+        self
+    }
+
+    pub fn with_settings(mut self, settings: Settings) -> Self {
+        self.settings = settings;
+        self
+    }
+
+    pub fn with_globals(mut self, globals: Globals) -> Self {
+        self.globals = globals;
+        self
+    }
+
+    pub fn into_globals(self) -> Globals {
+        self.globals
+    }
+
+    fn plain(&self, span: SourceSpan, unit: &Unit) -> Result<()> {
+        if !unit.is_empty() {
+            return Err(UnitNotAllowed {
+                src: self.source().named(),
+                bad_bit: span,
+                unit: unit.clone(),
+            })?;
+        }
+        Ok(())
+    }
+
+    fn real(&self, span: SourceSpan, num: Number) -> Result<f64> {
+        if num.is_complex() {
+            return Err(NotReal {
+                src: self.source().named(),
+                bad_bit: span,
+            })?;
+        }
+        Ok(num.to_f64())
+    }
+
+    // A plain real number such as the bound of a range
+    fn scalar(&self, span: SourceSpan, value: Value) -> Result<f64> {
+        let Value::Number(num, unit) = value else {
+            return Err(ExpectedNumber {
+                src: self.source().named(),
+                bad_bit: span,
+                kind: type_name(&value),
+            })?;
+        };
+        self.plain(span, &unit)?;
+        self.real(span, num)
+    }
+
+    // `start..end` counts up in steps of one, `start..=end` may include `end`
+    fn range(
+        &self,
+        span_a: SourceSpan,
+        start: Value,
+        span_b: SourceSpan,
+        end: Value,
+        inclusive: bool,
+    ) -> Result<Value> {
+        let start = self.scalar(span_a, start)?;
+        let end = self.scalar(span_b, end)?;
+        let len = if inclusive {
+            (end - start).floor() + 1.
+        } else {
+            (end - start).ceil()
+        };
+        // NaN bounds give a NaN length, which is no more usable than a huge one
+        if len.is_nan() || len > MAX_RANGE as f64 {
+            return Err(RangeTooLong {
+                src: self.source().named(),
+                bad_bit: join(span_a, span_b),
+                max: MAX_RANGE,
+            })?;
+        }
+        let items = (0..len.max(0.) as usize).map(|i| (start + i as f64).into());
+        Ok(Value::List(items.collect()))
+    }
+
+    fn integral(&self, span: SourceSpan, num: Number, unit: Unit) -> Result<BigInt> {
+        self.plain(span, &unit)?;
+        let num = match num {
+            Number::Int(num) => return Ok(num),
+            num => self.real(span, num)?,
+        };
+        let exact = Some(num).filter(|num| num.fract() == 0.0);
+        Ok(exact.and_then(BigInt::from_f64).ok_or(NonIntegralOperand {
+            src: self.source().named(),
+            bad_bit: span,
+        })?)
+    }
+
+    // Finite operands should give a finite result, anything else overflowed
+    fn finite(&self, span: SourceSpan, result: f64, operands: &[f64]) -> Result<f64> {
+        if self.settings.ieee || result.is_finite() || operands.iter().any(|x| !x.is_finite()) {
+            return Ok(result);
+        }
+        Err(Overflow {
+            src: self.source().named(),
+            bad_bit: span,
+        })?
+    }
+
+    // Expresses `b` in the unit of `a`, both must measure the same dimension
+    fn align(
+        &self,
+        span_a: SourceSpan,
+        unit_a: &Unit,
+        span_b: SourceSpan,
+        b: f64,
+        unit_b: &Unit,
+    ) -> Result<f64> {
+        if unit_a.dimension() != unit_b.dimension() {
+            return Err(DimensionMismatch {
+                src: self.source().named(),
+                left: span_a,
+                right: span_b,
+                left_unit: describe(unit_a),
+                right_unit: describe(unit_b),
+            })?;
+        }
+        Ok(b * unit_b.factor() / unit_a.factor())
+    }
+
+    // The unit of a product or quotient, `None` when a power left the range of `i8`
+    fn combine(
+        &self,
+        span_a: SourceSpan,
+        unit_a: &Unit,
+        span_b: SourceSpan,
+        unit_b: &Unit,
+        unit: Option<Unit>,
+    ) -> Result<Unit> {
+        Ok(unit.ok_or_else(|| UnitPowerRange {
+            src: self.source().named(),
+            left: span_a,
+            right: span_b,
+            left_unit: describe(unit_a),
+            right_unit: describe(unit_b),
+        })?)
+    }
+
+    // Only `+`, `-`, `*` and `/` are defined for complex operands
+    fn complex_op(
+        &self,
+        op: &str,
+        (span_a, a, unit_a): (SourceSpan, Number, Unit),
+        (span_b, b, unit_b): (SourceSpan, Number, Unit),
+    ) -> Result<(Number, Unit)> {
+        let span = join(span_a, span_b);
+        let (a, b) = (a.to_complex(), b.to_complex());
+        let (value, unit) = match op {
+            "+" | "-" => {
+                let b = b * self.align(span_a, &unit_a, span_b, 1.0, &unit_b)?;
+                (if op == "+" { a + b } else { a - b }, unit_a)
+            }
+            "*" => {
+                let unit = self.combine(span_a, &unit_a, span_b, &unit_b, unit_a.mul(&unit_b))?;
+                let (scale, unit) = units::simplify(1.0, unit);
+                (a * b * scale, unit)
+            }
+            "/" => {
+                if b == Complex64::ZERO && !self.settings.ieee {
+                    return Err(DivByZero {
+                        src: self.source().named(),
+                        bad_bit: span,
+                    })?;
+                }
+                let unit = self.combine(span_a, &unit_a, span_b, &unit_b, unit_a.div(&unit_b))?;
+                let (scale, unit) = units::simplify(1.0, unit);
+                (a / b * scale, unit)
+            }
+            _ => {
+                return Err(NotReal {
+                    src: self.source().named(),
+                    bad_bit: if a.im != 0.0 { span_a } else { span_b },
+                })?;
+            }
+        };
+        let operands = [a.re, a.im, b.re, b.im];
+        let value = Complex64::new(
+            self.finite(span, value.re, &operands)?,
+            self.finite(span, value.im, &operands)?,
+        );
+        Ok((Number::from_complex(value), unit))
+    }
+
+    fn map(&self, span: SourceSpan, value: Value, op: &UnaryFn) -> Result<Value> {
+        match value {
+            Value::Number(num, unit) => {
+                let (num, unit) = op(num, unit)?;
+                Ok(Value::Number(num, unit))
+            }
+            Value::Str(_) | Value::Function(_) => Err(ExpectedNumber {
+                src: self.source().named(),
+                bad_bit: span,
+                kind: type_name(&value),
+            })?,
+            Value::List(items) => items
+                .into_iter()
+                .map(|item| self.map(span, item, op))
+                .collect::<Result<_>>()
+                .map(Value::List),
+        }
+    }
+
+    // Lists pair up element by element with lists of the same length, a single
+    // number is spread across every element
+    fn broadcast(
+        &self,
+        span_a: SourceSpan,
+        a: Value,
+        span_b: SourceSpan,
+        b: Value,
+        op: &BinaryFn,
+    ) -> Result<Value> {
+        let scalar = |value: &Value| matches!(value, Value::Str(_) | Value::Function(_));
+        if scalar(&a) || scalar(&b) {
+            return Err(TypeMismatch {
+                src: self.source().named(),
+                left: span_a,
+                right: span_b,
+                left_type: type_name(&a),
+                right_type: type_name(&b),
+            })?;
+        }
+        let items = match (a, b) {
+            (Value::Number(a, unit_a), Value::Number(b, unit_b)) => {
+                let (num, unit) = op(a, unit_a, b, unit_b)?;
+                return Ok(Value::Number(num, unit));
+            }
+            (Value::List(xs), Value::List(ys)) => {
+                if xs.len() != ys.len() {
+                    return Err(LengthMismatch {
+                        src: self.source().named(),
+                        left: span_a,
+                        right: span_b,
+                        left_len: xs.len(),
+                        right_len: ys.len(),
+                    })?;
+                }
+                xs.into_iter()
+                    .zip(ys)
+                    .map(|(x, y)| self.broadcast(span_a, x, span_b, y, op))
+                    .collect::<Result<_>>()
+            }
+            (Value::List(xs), b) => xs
+                .into_iter()
+                .map(|x| self.broadcast(span_a, x, span_b, b.clone(), op))
+                .collect::<Result<_>>(),
+            (Value::Str(_) | Value::Function(_), _) | (_, Value::Str(_) | Value::Function(_)) => {
+                unreachable!()
+            }
+            (a, Value::List(ys)) => ys
+                .into_iter()
+                .map(|y| self.broadcast(span_a, a.clone(), span_b, y, op))
+                .collect::<Result<_>>(),
+        };
+        items.map(Value::List)
+    }
+
+    // Every number of the arguments, in order, for variadic functions
+    fn flatten(&self, span: SourceSpan, value: Value, into: &mut Vec<f64>) -> Result<()> {
+        match value {
+            Value::Number(num, unit) => {
+                self.plain(span, &unit)?;
+                into.push(self.real(span, num)?);
+            }
+            Value::Str(_) | Value::Function(_) => Err(ExpectedNumber {
+                src: self.source().named(),
+                bad_bit: span,
+                kind: type_name(&value),
+            })?,
+            Value::List(items) => {
+                for item in items {
+                    self.flatten(span, item, into)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn math(
+        &self,
+        span: SourceSpan,
+        native: &Native,
+        func: MathFn,
+        original: &[f64],
+    ) -> Result<Number> {
+        let mut args = original.to_vec();
+        let angle = self.settings.angle;
+        if native.angle == Angle::Takes {
+            args[0] = angle.to_radians(args[0]);
+        }
+        let result = func(&args);
+        let Number::Float(num) = result else {
+            return Ok(result);
+        };
+        let complex = natives::complex(native.name).filter(|_| self.settings.complex);
+        if let Some(func) = complex.filter(|_| num.is_nan()) {
+            let args: Vec<_> = original.iter().map(|&x| x.into()).collect();
+            return self.complex_math(span, native, func, &args);
+        }
+        if num.is_nan() && !self.settings.ieee && !args.iter().any(|x| x.is_nan()) {
+            return Err(DomainError {
+                src: self.source().named(),
+                bad_bit: span,
+                name: native.name,
+            })?;
+        }
+        let mut num = self.finite(span, num, &args)?;
+        if native.angle == Angle::Returns {
+            num = angle.from_radians(num);
+        }
+        Ok(num.into())
+    }
+
+    fn complex_math(
+        &self,
+        span: SourceSpan,
+        native: &Native,
+        func: ComplexFn,
+        args: &[Complex64],
+    ) -> Result<Number> {
+        let mut args = args.to_vec();
+        let angle = self.settings.angle;
+        if native.angle == Angle::Takes {
+            args[0] *= angle.to_radians(1.0);
+        }
+        let result = func(&args);
+        let operands: Vec<_> = args.iter().flat_map(|z| [z.re, z.im]).collect();
+        if result.is_nan() && !self.settings.ieee && !operands.iter().any(|x| x.is_nan()) {
+            return Err(DomainError {
+                src: self.source().named(),
+                bad_bit: span,
+                name: native.name,
+            })?;
+        }
+        let mut result = Complex64::new(
+            self.finite(span, result.re, &operands)?,
+            self.finite(span, result.im, &operands)?,
+        );
+        if native.angle == Angle::Returns && result.im == 0.0 {
+            result = angle.from_radians(result.re).into();
+        }
+        Ok(Number::from_complex(result))
+    }
+
+    // Applies a fixed-arity function element-wise when any argument is a list
+    fn call_each(
+        &self,
+        span: SourceSpan,
+        native: &Native,
+        func: MathFn,
+        args: Vec<(SourceSpan, Value)>,
+    ) -> Result<Value> {
+        let list = args.iter().find_map(|(arg_span, arg)| match arg {
+            Value::List(items) => Some((*arg_span, items.len())),
+            Value::Number(..) | Value::Str(_) | Value::Function(_) => None,
+        });
+        let Some((list_span, len)) = list else {
+            let mut nums = Vec::with_capacity(args.len());
+            for (arg_span, arg) in &args {
+                let Value::Number(num, unit) = arg else {
+                    return Err(ExpectedNumber {
+                        src: self.source().named(),
+                        bad_bit: *arg_span,
+                        kind: type_name(arg),
+                    })?;
+                };
+                self.plain(*arg_span, unit)?;
+                nums.push(num.clone());
+            }
+            let complex = args.iter().zip(&nums).find(|(_, num)| num.is_complex());
+            let result = match complex {
+                Some(((arg_span, _), _)) => {
+                    let func = natives::complex(native.name).ok_or(NotReal {
+                        src: self.source().named(),
+                        bad_bit: *arg_span,
+                    })?;
+                    let nums: Vec<_> = nums.iter().map(Number::to_complex).collect();
+                    self.complex_math(span, native, func, &nums)?
+                }
+                None => {
+                    let nums: Vec<_> = nums.iter().map(Number::to_f64).collect();
+                    self.math(span, native, func, &nums)?
+                }
+            };
+            return Ok(Value::Number(result, Unit::default()));
+        };
+        let mut columns = vec![Vec::with_capacity(args.len()); len];
+        for (arg_span, arg) in args {
+            match arg {
+                Value::List(items) if items.len() == len => {
+                    for (column, item) in columns.iter_mut().zip(items) {
+                        column.push((arg_span, item));
+                    }
+                }
+                Value::List(items) => Err(LengthMismatch {
+                    src: self.source().named(),
+                    left: list_span,
+                    right: arg_span,
+                    left_len: len,
+                    right_len: items.len(),
+                })?,
+                number => {
+                    for column in columns.iter_mut() {
+                        column.push((arg_span, number.clone()));
+                    }
+                }
+            }
+        }
+        columns
+            .into_iter()
+            .map(|column| self.call_each(span, native, func, column))
+            .collect::<Result<_>>()
+            .map(Value::List)
+    }
+
+    fn call(
+        &mut self,
+        span: SourceSpan,
+        native: &Native,
+        args: Vec<(SourceSpan, Value)>,
+    ) -> Result<Value> {
+        match native.func {
+            Func::Math(func) if matches!(native.arity, Arity::Values(_)) => {
+                let mut nums = Vec::new();
+                for (arg_span, arg) in args {
+                    self.flatten(arg_span, arg, &mut nums)?;
+                }
+                if !native.arity.accepts(nums.len()) {
+                    return Err(EmptyList {
+                        src: self.source().named(),
+                        bad_bit: span,
+                        name: native.name,
+                    })?;
+                }
+                Ok(Value::Number(
+                    self.math(span, native, func, &nums)?,
+                    Unit::default(),
+                ))
+            }
+            Func::Math(func) => self.call_each(span, native, func, args),
+            Func::Builtin(builtin) => self.builtin(span, builtin, args),
+        }
+    }
+
+    fn builtin(
+        &mut self,
+        span: SourceSpan,
+        builtin: Builtin,
+        mut args: Vec<(SourceSpan, Value)>,
+    ) -> Result<Value> {
+        let (arg_span, arg) = args.remove(0);
+        match builtin {
+            Builtin::Len => match arg {
+                Value::List(items) => Ok((items.len() as f64).into()),
+                Value::Str(text) => Ok((text.chars().count() as f64).into()),
+                Value::Number(..) | Value::Function(_) => Err(NotAList {
+                    src: self.source().named(),
+                    bad_bit: arg_span,
+                })?,
+            },
+            Builtin::Concat => {
+                let (base, style) = (self.settings.base, self.settings.style);
+                let text = std::iter::once(arg)
+                    .chain(args.into_iter().map(|(_, arg)| arg))
+                    .map(|arg| format::format_value(&arg, base, style))
+                    .collect();
+                Ok(Value::Str(text))
+            }
+            Builtin::Format => {
+                let Value::Str(template) = arg else {
+                    return Err(FormatError {
+                        src: self.source().named(),
+                        bad_bit: arg_span,
+                        reason: "the first argument must be a string".to_string(),
+                    })?;
+                };
+                let args: Vec<_> = args.into_iter().map(|(_, arg)| arg).collect();
+                let text =
+                    format::render(&template, &args, self.settings.base, self.settings.style)
+                        .map_err(|reason| FormatError {
+                            src: self.source().named(),
+                            bad_bit: arg_span,
+                            reason,
+                        })?;
+                Ok(Value::Str(text))
+            }
+            Builtin::Transpose => {
+                let matrix = self.matrix(arg_span, arg)?;
+                Ok(from_matrix(matrix::transpose(&matrix)))
+            }
+            Builtin::Det => {
+                let matrix = self.square(arg_span, arg)?;
+                Ok(matrix::determinant(&matrix).into())
+            }
+            Builtin::Inverse => {
+                let matrix = self.square(arg_span, arg)?;
+                let inverse = matrix::inverse(&matrix).ok_or(SingularMatrix {
+                    src: self.source().named(),
+                    bad_bit: arg_span,
+                })?;
+                Ok(from_matrix(inverse))
+            }
+            Builtin::LinSolve => {
+                let a = self.square(arg_span, arg)?;
+                let (b_span, b) = args.remove(0);
+                let column = is_vector(&b);
+                let b = if column {
+                    self.column(b_span, b)?
+                } else {
+                    self.matrix(b_span, b)?
+                };
+                if a.len() != b.len() {
+                    return Err(ShapeMismatch {
+                        src: self.source().named(),
+                        left: arg_span,
+                        right: b_span,
+                        left_shape: describe_shape(&a),
+                        right_shape: describe_shape(&b),
+                    })?;
+                }
+                let x = matrix::solve(&a, &b).ok_or(SingularMatrix {
+                    src: self.source().named(),
+                    bad_bit: arg_span,
+                })?;
+                if column {
+                    Ok(from_vector(matrix::transpose(&x).swap_remove(0)))
+                } else {
+                    Ok(from_matrix(x))
+                }
+            }
+            Builtin::Map => {
+                let items = self.items(arg_span, arg)?;
+                let (function_span, function) = args.remove(0);
+                let function = self.function(function_span, function)?;
+                items
+                    .into_iter()
+                    .map(|item| self.apply(function_span, &function, vec![(arg_span, item)]))
+                    .collect::<Result<_>>()
+                    .map(Value::List)
+            }
+            // Keeps the items the function gives a non-zero number for
+            Builtin::Filter => {
+                let items = self.items(arg_span, arg)?;
+                let (function_span, function) = args.remove(0);
+                let function = self.function(function_span, function)?;
+                let mut kept = Vec::new();
+                for item in items {
+                    let keep =
+                        self.apply(function_span, &function, vec![(arg_span, item.clone())])?;
+                    if self.scalar(function_span, keep)? != 0.0 {
+                        kept.push(item);
+                    }
+                }
+                Ok(Value::List(kept))
+            }
+            Builtin::Reduce => {
+                let mut items = self.items(arg_span, arg)?.into_iter();
+                let (function_span, function) = args.remove(0);
+                let function = self.function(function_span, function)?;
+                let initial = match args.pop() {
+                    Some((_, initial)) => Some(initial),
+                    None => items.next(),
+                };
+                let mut total = initial.ok_or(EmptyList {
+                    src: self.source().named(),
+                    bad_bit: arg_span,
+                    name: "reduce",
+                })?;
+                for item in items {
+                    let args = vec![(arg_span, total), (arg_span, item)];
+                    total = self.apply(function_span, &function, args)?;
+                }
+                Ok(total)
+            }
+            Builtin::SortBy => {
+                let items = self.items(arg_span, arg)?;
+                let (function_span, function) = args.remove(0);
+                let function = self.function(function_span, function)?;
+                let mut keyed = Vec::with_capacity(items.len());
+                for item in items {
+                    let key =
+                        self.apply(function_span, &function, vec![(arg_span, item.clone())])?;
+                    keyed.push((self.scalar(function_span, key)?, item));
+                }
+                keyed.sort_by(|(a, _), (b, _)| a.total_cmp(b));
+                Ok(Value::List(
+                    keyed.into_iter().map(|(_, item)| item).collect(),
+                ))
+            }
+            Builtin::Compose => {
+                let mut functions = vec![self.function(arg_span, arg)?];
+                for (span, function) in args {
+                    functions.push(self.function(span, function)?);
+                }
+                if functions.len() == 1 {
+                    return Ok(Value::Function(functions.swap_remove(0)));
+                }
+                Ok(Value::Function(Rc::new(Function::Compose(functions))))
+            }
+            Builtin::Solve | Builtin::Integrate | Builtin::Diff => {
+                let function = self.function(arg_span, arg)?;
+                // The name of the variable is only needed by the parser
+                args.remove(0);
+                let mut points = Vec::new();
+                for (span, point) in args {
+                    points.push(self.scalar(span, point)?);
+                }
+                let mut f = |x: f64| {
+                    let value = self.apply(arg_span, &function, vec![(arg_span, x.into())])?;
+                    self.scalar(arg_span, value)
+                };
+                let (name, outcome) = match builtin {
+                    Builtin::Solve => ("solve", numeric::solve(&mut f, points[0])?),
+                    Builtin::Integrate => (
+                        "integrate",
+                        numeric::integrate(&mut f, points[0], points[1])?,
+                    ),
+                    _ => ("diff", numeric::diff(&mut f, points[0])?),
+                };
+                let result = outcome.map_err(|reason| NoConvergence {
+                    src: self.source().named(),
+                    bad_bit: span,
+                    name,
+                    reason,
+                })?;
+                Ok(result.into())
+            }
+        }
+    }
+
+    fn items(&self, span: SourceSpan, value: Value) -> Result<Vec<Value>> {
+        match value {
+            Value::List(items) => Ok(items),
+            _ => Err(NotAList {
+                src: self.source().named(),
+                bad_bit: span,
+            })?,
+        }
+    }
+
+    fn function(&self, span: SourceSpan, value: Value) -> Result<Rc<Function>> {
+        match value {
+            Value::Function(function) => Ok(function),
+            _ => Err(NotAFunction {
+                src: self.source().named(),
+                bad_bit: span,
+                kind: type_name(&value),
+            })?,
+        }
+    }
+
+    // The source of the code running now, that of the function inside a call
+    fn source(&self) -> Source<'_> {
+        self.shared
+            .as_ref()
+            .map_or(self.src, |shared| shared.source())
+    }
+
+    // Captures the variables the lambda uses that are set right now, the others
+    // are looked up when it is called
+    fn closure(&mut self, lambda: Rc<Lambda>) -> Value {
+        let source = self
+            .shared
+            .get_or_insert_with(|| Rc::new(self.src.into()))
+            .clone();
+        let captures = lambda
+            .names
+            .iter()
+            .filter_map(|name| Some((name.clone(), self.globals.get(name)?.clone())))
+            .collect();
+        Value::Function(Rc::new(Function::Closure {
+            lambda,
+            captures,
+            source,
+        }))
+    }
+
+    // Runs the body of a function as a frame of this VM, with the variables of
+    // the caller, its captures and its arguments
+    fn apply(
+        &mut self,
+        span: SourceSpan,
+        function: &Function,
+        args: Vec<(SourceSpan, Value)>,
+    ) -> Result<Value> {
+        if function.arity() != args.len() {
+            return Err(FunctionArity {
+                src: self.source().named(),
+                bad_bit: span,
+                arity: Arity::Exact(function.arity()),
+                got: args.len(),
+                function: function.to_string(),
+            })?;
+        }
+        let (lambda, captures, source) = match function {
+            Function::Closure {
+                lambda,
+                captures,
+                source,
+            } => (lambda, captures, source),
+            Function::Compose(functions) => {
+                let mut args = args;
+                for function in functions.iter().rev() {
+                    let value = self.apply(span, function, args)?;
+                    args = vec![(span, value)];
+                }
+                return Ok(args.swap_remove(0).1);
+            }
+        };
+        if self.depth == MAX_DEPTH {
+            return Err(TooDeep {
+                src: self.source().named(),
+                bad_bit: span,
+                max: MAX_DEPTH,
+            })?;
+        }
+        // Everything the body may assign, the call leaves the caller's variables alone
+        let mut saved = Vec::new();
+        let params = lambda.params.iter().cloned();
+        let bindings = captures
+            .iter()
+            .cloned()
+            .chain(params.zip(args.into_iter().map(|(_, arg)| arg)));
+        for (name, value) in bindings {
+            saved.push((name.clone(), self.globals.insert(name, value)));
+        }
+        for name in &lambda.names {
+            if !saved.iter().any(|(saved, _)| saved == name) {
+                saved.push((name.clone(), self.globals.get(name).cloned()));
+            }
+        }
+        // The body runs on top of the caller's stack, which is left as it was
+        let base = self.stack.len();
+        let caller = (
+            std::mem::replace(&mut self.chunk, lambda.chunk.clone()),
+            std::mem::replace(&mut self.ip, 0),
+            self.shared.replace(source.clone()),
+        );
+        self.depth += 1;
+        let value = self.run(&mut String::new());
+        self.depth -= 1;
+        (self.chunk, self.ip, self.shared) = caller;
+        self.stack.truncate(base);
+        for (name, value) in saved {
+            match value {
+                Some(value) => self.globals.insert(name, value),
+                None => self.globals.remove(&name),
+            };
+        }
+        // Runaway recursion stops with a single error rather than one per call
+        value.map_err(|error| {
+            if error.is::<TooDeep>() || error.is::<BudgetExhausted>() {
+                return error;
+            }
+            CallError {
+                src: self.source().named(),
+                bad_bit: span,
+                function: function.to_string(),
+                errors: vec![error],
+            }
+            .into()
+        })
+    }
+
+    // A list of rows of plain numbers, every row of the same non-zero length
+    fn matrix(&self, span: SourceSpan, value: Value) -> Result<Matrix> {
+        let not_matrix = || NotAMatrix {
+            src: self.source().named(),
+            bad_bit: span,
+        };
+        let Value::List(rows) = value else {
+            return Err(not_matrix())?;
+        };
+        let mut matrix = Vec::with_capacity(rows.len());
+        for row in rows {
+            let Value::List(items) = row else {
+                return Err(not_matrix())?;
+            };
+            let mut nums = Vec::with_capacity(items.len());
+            for item in items {
+                let Value::Number(num, unit) = item else {
+                    return Err(not_matrix())?;
+                };
+                self.plain(span, &unit)?;
+                nums.push(self.real(span, num)?);
+            }
+            matrix.push(nums);
+        }
+        let cols = matrix::shape(&matrix).1;
+        if cols == 0 || matrix.iter().any(|row| row.len() != cols) {
+            return Err(not_matrix())?;
+        }
+        Ok(matrix)
+    }
+
+    fn column(&self, span: SourceSpan, vector: Value) -> Result<Matrix> {
+        Ok(matrix::transpose(
+            &self.matrix(span, Value::List(vec![vector]))?,
+        ))
+    }
+
+    fn square(&self, span: SourceSpan, value: Value) -> Result<Matrix> {
+        let matrix = self.matrix(span, value)?;
+        let (rows, cols) = matrix::shape(&matrix);
+        if rows != cols {
+            return Err(NotSquare {
+                src: self.source().named(),
+                bad_bit: span,
+                shape: describe_shape(&matrix),
+            })?;
+        }
+        Ok(matrix)
+    }
+
+    // Matrix product, a vector on the left is a row and on the right a column
+    fn mat_mul(&self, span_a: SourceSpan, a: Value, span_b: SourceSpan, b: Value) -> Result<Value> {
+        let (row, column) = (is_vector(&a), is_vector(&b));
+        let a = if row {
+            self.matrix(span_a, Value::List(vec![a]))?
+        } else {
+            self.matrix(span_a, a)?
+        };
+        let b = if column {
+            self.column(span_b, b)?
+        } else {
+            self.matrix(span_b, b)?
+        };
+        if matrix::shape(&a).1 != b.len() {
+            return Err(ShapeMismatch {
+                src: self.source().named(),
+                left: span_a,
+                right: span_b,
+                left_shape: describe_shape(&a),
+                right_shape: describe_shape(&b),
+            })?;
+        }
+        let mut product = matrix::multiply(&a, &b);
+        Ok(match (row, column) {
+            (true, true) => product[0][0].into(),
+            (true, false) => from_vector(product.swap_remove(0)),
+            (false, true) => from_vector(matrix::transpose(&product).swap_remove(0)),
+            (false, false) => from_matrix(product),
+        })
+    }
+
+    fn index(
+        &self,
+        span: SourceSpan,
+        list: Value,
+        index_span: SourceSpan,
+        index: Value,
+    ) -> Result<Value> {
+        let Value::List(mut items) = list else {
+            return Err(NotAList {
+                src: self.source().named(),
+                bad_bit: span,
+            })?;
+        };
+        let out_of_range = || IndexOutOfRange {
+            src: self.source().named(),
+            bad_bit: index_span,
+            len: items.len(),
+        };
+        let Value::Number(index, unit) = index else {
+            return Err(ExpectedNumber {
+                src: self.source().named(),
+                bad_bit: index_span,
+                kind: type_name(&index),
+            })?;
+        };
+        self.plain(index_span, &unit)?;
+        let index = self.real(index_span, index)?;
+        // Negative indexes count from the end, `xs[-1]` is the last element
+        let position = if index < 0. {
+            index + items.len() as f64
+        } else {
+            index
+        };
+        if position.fract() != 0.0 || position < 0. || position >= items.len() as f64 {
+            return Err(out_of_range())?;
+        }
+        Ok(items.swap_remove(position as usize))
+    }
+
+    // Runs another script with the variables and settings of this one, its path
+    // is relative to the importing script
+    fn import(&mut self, span: SourceSpan, path: &str) -> Result<Value> {
+        let base = self.imports.last().and_then(|file| file.parent());
+        let file = base.unwrap_or(Path::new(".")).join(path);
+        let read = fs::canonicalize(&file).and_then(|file| Ok((fs::read_to_string(&file)?, file)));
+        let (source, file) = read.map_err(|error| ImportFailed {
+            src: self.source().named(),
+            bad_bit: span,
+            path: path.to_string(),
+            reason: error.to_string(),
+        })?;
+        if self.imports.contains(&file) {
+            let chain: Vec<_> = self
+                .imports
+                .iter()
+                .chain([&file])
+                .map(|file| file.display().to_string())
+                .collect();
+            return Err(ImportCycle {
+                src: self.source().named(),
+                bad_bit: span,
+                chain: chain.join(" -> "),
+            })?;
+        }
+        let src = self.source().named();
+        let failed = |error| ImportError {
+            src: src.clone(),
+            bad_bit: span,
+            path: path.to_string(),
+            errors: vec![error],
+        };
+        // Named like the path of the importing script, so errors point at the file
+        let parent = Path::new(self.source().name).parent();
+        let name = parent.unwrap_or(Path::new("")).join(path);
+        let name = name.display().to_string();
+        let chunk = compiler::compile_source(Source::new(&name, &source)).map_err(failed)?;
+        let mut imports = self.imports.clone();
+        imports.push(file);
+        let mut vm = Vm::new(&source, chunk)
+            .with_name(&name)
+            .with_settings(self.settings)
+            .with_globals(std::mem::take(&mut self.globals));
+        vm.imports = imports;
+        vm.steps = self.steps;
+        vm.budget = self.budget;
+        let output = vm.eval();
+        self.steps = vm.steps;
+        self.globals = vm.into_globals();
+        Ok(Value::Str(output.map_err(failed)?))
+    }
+
     pub fn eval(&mut self) -> Result<String> {
         let mut result = String::new();
+        let value = self.run(&mut result)?;
+        write!(
+            &mut result,
+            "{}",
+            format::format_value(&value, self.settings.base, self.settings.style)
+        )
+        .expect("Failed to write to result buffer");
+        Ok(result)
+    }
+
+    // Runs up to the return opcode, earlier statements print to `output`
+    fn run(&mut self, output: &mut String) -> Result<Value> {
         use Opcode::*;
         if self.ip >= self.chunk.len() {
             return Err(NoReturnOpcode {})?;
         }
         loop {
+            if self.steps == self.budget {
+                return Err(BudgetExhausted {
+                    budget: self.budget,
+                })?;
+            }
+            self.steps += 1;
             let instruction = &self.chunk[self.ip];
             self.ip += 1;
             match instruction {
                 Add => binary_op!(self, +),
                 Sub => binary_op!(self, -),
-                Mult => binary_op!(self, *),
-                Mod => binary_op!(self, %),
-                Div => binary_op!(self, /),
+                Mult => scaling_op!(self, *, mul),
+                MatMul => {
+                    let (span_b, b) = self.stack.pop()?;
+                    let (span_a, a) = self.stack.pop()?;
+                    let value = self.mat_mul(span_a, a, span_b, b)?;
+                    self.stack.push((join(span_a, span_b), value))?;
+                }
+                Mod => {
+                    let modulo = self.settings.modulo;
+                    binary_op!(self, %, |a, b| modulo.apply(a, b))
+                }
+                Div => scaling_op!(self, /, div),
+                IntDiv => scaling_op!(self, /, div, f64::floor, "//"),
+                BitAnd => bitwise_op!(self, &),
+                BitOr => bitwise_op!(self, |),
+                Xor => bitwise_op!(self, ^),
+                Shl => shift_op!(self, <<),
+                Shr => shift_op!(self, >>),
+                Percent => {
+                    let (span, value) = self.stack.pop()?;
+                    let value = self.map(span, value, &|num, unit| {
+                        Ok(((self.real(span, num)? / 100.).into(), unit))
+                    })?;
+                    self.stack.push((span, value))?;
+                }
+                // `a ^ b` is `pow(a, b)`
+                Pow => {
+                    let (span_b, b) = self.stack.pop()?;
+                    let (span_a, a) = self.stack.pop()?;
+                    let span = join(span_a, span_b);
+                    let pow = natives::lookup("pow").unwrap();
+                    let value = self.call(span, pow, vec![(span_a, a), (span_b, b)])?;
+                    self.stack.push((span, value))?;
+                }
+                PercentAdd => percent_op!(self, +),
+                PercentSub => percent_op!(self, -),
                 Neg => {
-                    let (offset, num) = self.stack.pop()?;
-                    self.stack.push((offset, num * -1.0))?;
+                    let (span, value) = self.stack.pop()?;
+                    let value = self.map(span, value, &|num, unit| Ok((-num, unit)))?;
+                    self.stack.push((span, value))?;
+                }
+                BitNot => {
+                    let (span, value) = self.stack.pop()?;
+                    let value = self.map(span, value, &|num, unit| {
+                        let num = self.integral(span, num, unit)?;
+                        Ok((Number::Int(!num), Unit::default()))
+                    })?;
+                    self.stack.push((span, value))?;
+                }
+                Num(span, num) => {
+                    self.stack.push((*span, (*num).into()))?;
+                }
+                Overflowed(span) => {
+                    self.finite(*span, f64::INFINITY, &[])?;
+                }
+                Int(span, num) => {
+                    let num = Number::Int(num.clone());
+                    self.stack
+                        .push((*span, Value::Number(num, Unit::default())))?;
+                }
+                Imaginary(span, num) => {
+                    let num = Number::from_complex(Complex64::new(0.0, *num));
+                    self.stack
+                        .push((*span, Value::Number(num, Unit::default())))?;
+                }
+                Str(span, text) => {
+                    self.stack.push((*span, Value::Str(text.clone())))?;
+                }
+                Quantity(span, num, unit) => {
+                    let (num, unit) = units::simplify(*num, unit.clone());
+                    self.stack.push((*span, Value::Number(num.into(), unit)))?;
+                }
+                Convert(target_span, target) => {
+                    let (span, value) = self.stack.pop()?;
+                    let value = self.map(span, value, &|num, unit| {
+                        if unit.dimension() != target.dimension() {
+                            return Err(DimensionMismatch {
+                                src: self.source().named(),
+                                left: span,
+                                right: *target_span,
+                                left_unit: describe(&unit),
+                                right_unit: describe(target),
+                            })?;
+                        }
+                        let scale = unit.factor() / target.factor();
+                        let converted = match num {
+                            Number::Complex(num) => Number::Complex(num * scale),
+                            num => (num.to_f64() * scale).into(),
+                        };
+                        Ok((converted, target.clone()))
+                    })?;
+                    self.stack.push((join(span, *target_span), value))?;
+                }
+                List(span, len) => {
+                    let mut items = Vec::with_capacity(*len);
+                    for _ in 0..*len {
+                        items.push(self.stack.pop()?.1);
+                    }
+                    items.reverse();
+                    self.stack.push((*span, Value::List(items)))?;
+                }
+                Index(bracket) => {
+                    let (index_span, index) = self.stack.pop()?;
+                    let (span, list) = self.stack.pop()?;
+                    let item = self.index(span, list, index_span, index)?;
+                    self.stack.push((join(span, *bracket), item))?;
+                }
+                GetVar(span, name) => {
+                    let value = self.globals.get(name).cloned().ok_or(UndefinedVariable {
+                        src: self.source().named(),
+                        bad_bit: *span,
+                    })?;
+                    self.stack.push((*span, value))?;
+                }
+                Import(span, path) => {
+                    let (span, path) = (*span, path.clone());
+                    let value = self.import(span, &path)?;
+                    self.stack.push((span, value))?;
+                }
+                SetVar(name) => {
+                    let (span, value) = self.stack.pop()?;
+                    self.globals.insert(name.clone(), value.clone());
+                    self.stack.push((span, value))?;
+                }
+                Call(span, native, argc) => {
+                    let (span, native, argc) = (*span, *native, *argc);
+                    let mut args = Vec::with_capacity(argc);
+                    for _ in 0..argc {
+                        args.push(self.stack.pop()?);
+                    }
+                    args.reverse();
+                    let value = self.call(span, native, args)?;
+                    self.stack.push((span, value))?;
+                }
+                Fact(bang) => {
+                    let (span, value) = self.stack.pop()?;
+                    let result = join(span, *bang);
+                    let value = self.map(span, value, &|num, unit| {
+                        self.plain(span, &unit)?;
+                        let n = match num {
+                            Number::Int(num) => num.to_u64(),
+                            Number::Float(num) if num.fract() == 0.0 && num >= 0.0 => {
+                                Some(num as u64)
+                            }
+                            Number::Float(_) | Number::Complex(_) => None,
+                        };
+                        let n = n.ok_or(InvalidFactorial {
+                            src: self.source().named(),
+                            bad_bit: span,
+                        })?;
+                        let value = if n > number::MAX_FACTORIAL {
+                            self.finite(result, f64::INFINITY, &[])?.into()
+                        } else {
+                            Number::Int(number::factorial(n))
+                        };
+                        Ok((value, Unit::default()))
+                    })?;
+                    self.stack.push((result, value))?;
+                }
+                Range(inclusive) => {
+                    let (span_b, end) = self.stack.pop()?;
+                    let (span_a, start) = self.stack.pop()?;
+                    let value = self.range(span_a, start, span_b, end, *inclusive)?;
+                    self.stack.push((join(span_a, span_b), value))?;
+                }
+                // Leaves the results, the items and the position of the loop on the stack
+                Iter => {
+                    let (span, items) = self.stack.pop()?;
+                    if !matches!(items, Value::List(_)) {
+                        return Err(NotAList {
+                            src: self.source().named(),
+                            bad_bit: span,
+                        })?;
+                    }
+                    self.stack.push((span, Value::List(Vec::new())))?;
+                    self.stack.push((span, items))?;
+                    self.stack.push((span, 0.0.into()))?;
+                }
+                Next(name, exit) => {
+                    let (span, position) = self.stack.pop()?;
+                    let (_, items) = self.stack.pop()?;
+                    let (Value::Number(position, _), Value::List(list)) = (&position, &items)
+                    else {
+                        unreachable!()
+                    };
+                    let position = position.to_f64();
+                    match list.get(position as usize) {
+                        Some(item) => {
+                            self.globals.insert(name.clone(), item.clone());
+                            self.stack.push((span, items))?;
+                            self.stack.push((span, (position + 1.).into()))?;
+                        }
+                        None => self.ip = *exit,
+                    }
                 }
-                Num(offset, num) => {
-                    self.stack.push((*offset, *num))?;
+                Collect => {
+                    let (_, value) = self.stack.pop()?;
+                    let position = self.stack.pop()?;
+                    let items = self.stack.pop()?;
+                    let (span, results) = self.stack.pop()?;
+                    let Value::List(mut results) = results else {
+                        unreachable!()
+                    };
+                    results.push(value);
+                    self.stack.push((span, Value::List(results)))?;
+                    self.stack.push(items)?;
+                    self.stack.push(position)?;
                 }
-                Ret => {
-                    let (_, ret) = self.stack.pop()?;
-                    write!(&mut result, "{}", ret).expect("Failed to write to result buffer");
-                    break;
+                Loop(head) => self.ip = *head,
+                Closure(lambda) => {
+                    let lambda = lambda.clone();
+                    let span = lambda.span;
+                    let value = self.closure(lambda);
+                    self.stack.push((span, value))?;
                 }
+                Apply(span, argc) => {
+                    let (span, argc) = (*span, *argc);
+                    let mut args = Vec::with_capacity(argc);
+                    for _ in 0..argc {
+                        args.push(self.stack.pop()?);
+                    }
+                    args.reverse();
+                    let (callee_span, callee) = self.stack.pop()?;
+                    let function = self.function(callee_span, callee)?;
+                    let span = join(callee_span, span);
+                    let value = self.apply(span, &function, args)?;
+                    self.stack.push((span, value))?;
+                }
+                Pop => {
+                    self.stack.pop()?;
+                }
+                // The result of a statement before the last one
+                Print => {
+                    let (_, value) = self.stack.pop()?;
+                    if self.settings.echo == Echo::All {
+                        let text =
+                            format::format_value(&value, self.settings.base, self.settings.style);
+                        writeln!(output, "{text}").expect("Failed to write to result buffer");
+                    }
+                }
+                Ret => return Ok(self.stack.pop()?.1),
                 Nop => (),
             }
         }
-        Ok(result)
     }
 }
 
@@ -105,18 +1941,19 @@ mod test {
     use super::*;
 
     macro_rules! define_op {
-        ($chunk: expr, $op_code: expr) => {
-            $chunk.push(Opcode::Num(0, 20.));
-            $chunk.push(Opcode::Num(1, 10.));
-            $chunk.push($op_code);
-            $chunk.push(Opcode::Ret);
+        ($op_code: expr) => {
+            vec![
+                Opcode::Num((0, 2).into(), 20.),
+                Opcode::Num((5, 2).into(), 10.),
+                $op_code,
+                Opcode::Ret,
+            ]
         };
     }
 
     #[test]
     fn vm_add() {
-        let mut chunk = Chunk::new();
-        define_op!(chunk, Opcode::Add);
+        let chunk = define_op!(Opcode::Add);
         let mut vm = Vm::new("20 + 10", chunk);
         let result = vm.eval().unwrap();
         assert_eq!(result, "30");
@@ -124,8 +1961,7 @@ mod test {
 
     #[test]
     fn vm_sub() {
-        let mut chunk = Chunk::new();
-        define_op!(chunk, Opcode::Sub);
+        let chunk = define_op!(Opcode::Sub);
         let mut vm = Vm::new("20 - 10", chunk);
         let result = vm.eval().unwrap();
         assert_eq!(result, "10");
@@ -133,8 +1969,7 @@ mod test {
 
     #[test]
     fn vm_div() {
-        let mut chunk = Chunk::new();
-        define_op!(chunk, Opcode::Div);
+        let chunk = define_op!(Opcode::Div);
         let mut vm = Vm::new("20 / 10", chunk);
         let result = vm.eval().unwrap();
         assert_eq!(result, "2");
@@ -142,8 +1977,7 @@ mod test {
 
     #[test]
     fn vm_mult() {
-        let mut chunk = Chunk::new();
-        define_op!(chunk, Opcode::Mult);
+        let chunk = define_op!(Opcode::Mult);
         let mut vm = Vm::new("20 * 10", chunk);
         let result = vm.eval().unwrap();
         assert_eq!(result, "200");
@@ -151,20 +1985,99 @@ mod test {
 
     #[test]
     fn vm_mod() {
-        let mut chunk = Chunk::new();
-        define_op!(chunk, Opcode::Mod);
+        let chunk = define_op!(Opcode::Mod);
         let mut vm = Vm::new("20 % 10", chunk);
         let result = vm.eval().unwrap();
         assert_eq!(result, "0");
     }
     #[test]
     fn vm_neg() {
-        let mut chunk = Chunk::new();
-        chunk.push(Opcode::Num(0, 20.));
-        chunk.push(Opcode::Neg);
-        chunk.push(Opcode::Ret);
+        let chunk = vec![Opcode::Num((1, 2).into(), 20.), Opcode::Neg, Opcode::Ret];
         let mut vm = Vm::new("-20", chunk);
         let result = vm.eval().unwrap();
         assert_eq!(result, "-20");
     }
+
+    #[test]
+    fn vm_bitwise() {
+        for (op, expected) in [
+            (Opcode::BitAnd, "0"),
+            (Opcode::BitOr, "30"),
+            (Opcode::Xor, "30"),
+            (Opcode::Shl, "20480"),
+            (Opcode::Shr, "0"),
+        ] {
+            let mut vm = Vm::new("20 ? 10", define_op!(op));
+            assert_eq!(vm.eval().unwrap(), expected);
+        }
+    }
+
+    #[test]
+    fn vm_bitwise_rejects_fractions() {
+        let chunk = vec![
+            Opcode::Num((0, 1).into(), 6.),
+            Opcode::Num((4, 3).into(), 2.5),
+            Opcode::BitAnd,
+            Opcode::Ret,
+        ];
+        let mut vm = Vm::new("6 & 2.5", chunk);
+        let err = vm.eval().unwrap_err();
+        let label = err.labels().unwrap().next().unwrap();
+        assert_eq!((label.offset(), label.len()), (4, 3));
+    }
+
+    #[test]
+    fn vm_shift_out_of_range() {
+        let chunk = vec![
+            Opcode::Num((0, 1).into(), 1.),
+            Opcode::Num((5, 2).into(), 64.),
+            Opcode::Shl,
+            Opcode::Ret,
+        ];
+        let mut vm = Vm::new("1 << 64", chunk);
+        assert!(vm.eval().is_err());
+    }
+
+    #[test]
+    fn vm_output_base() {
+        let chunk = vec![Opcode::Num((0, 3).into(), 255.), Opcode::Ret];
+        let settings = Settings {
+            base: Base::Hex,
+            ..Default::default()
+        };
+        let mut vm = Vm::new("255", chunk).with_settings(settings);
+        assert_eq!(vm.eval().unwrap(), "0xff");
+    }
+
+    #[test]
+    fn vm_ieee_mode() {
+        let chunk = || {
+            vec![
+                Opcode::Num((0, 1).into(), 1.),
+                Opcode::Num((4, 1).into(), 0.),
+                Opcode::Div,
+                Opcode::Ret,
+            ]
+        };
+        assert!(Vm::new("1 / 0", chunk()).eval().is_err());
+        let settings = Settings {
+            ieee: true,
+            ..Default::default()
+        };
+        let mut vm = Vm::new("1 / 0", chunk()).with_settings(settings);
+        assert_eq!(vm.eval().unwrap(), "inf");
+    }
+
+    #[test]
+    fn vm_overflow() {
+        let chunk = vec![
+            Opcode::Num((0, 5).into(), 1e308),
+            Opcode::Num((8, 2).into(), 10.),
+            Opcode::Mult,
+            Opcode::Ret,
+        ];
+        let mut vm = Vm::new("1e308 * 10", chunk);
+        let err = vm.eval().unwrap_err();
+        assert_eq!(err.to_string(), "Result is infinite!");
+    }
 }